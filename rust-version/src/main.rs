@@ -1,232 +1,2397 @@
-use reqwest::{Client, get};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::stream::{self, StreamExt};
+use clap::{ArgAction, CommandFactory, Parser, Subcommand, ValueEnum};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
 use serde_json::{Value, json};
-use std::collections::{BTreeMap, HashMap};
-use std::io::{self, Write};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, IsTerminal, Write};
+use std::net::IpAddr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 use tokio::fs;
+use tokio::sync::Semaphore;
 use tokio::task;
 use std::cmp::Ordering;
-use tokio::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use haversine::{distance, Location, Units};
-use tokio::io::AsyncWriteExt;
+use std::time::{Duration, Instant};
 
-pub async fn get_key(client: &Client, token: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let res = client
+// A blip after the token was typed shouldn't end the run: connection errors and 5xx answers are retried after 1s, 2s, 4s, ...
+const API_RETRY_ATTEMPTS: u32 = 3;
+const API_RETRY_DELAY: Duration = Duration::from_secs(1);
+// Upper bound for a whole request, so a hung connection fails (and is retried) instead of blocking forever
+const API_TIMEOUT: Duration = Duration::from_secs(30);
+const DNS_CONCURRENCY: usize = 32;
+const SERVER_LIMIT: usize = 7000;
+// One recommendations request per country; the pause keeps a full run from hammering the endpoint
+const RECOMMENDATION_LIMIT: usize = 100;
+const RECOMMENDATION_DELAY: Duration = Duration::from_millis(250);
+const GEOIP_CACHE_DIR: &str = ".geoip";
+const LOCATION_PRESETS: &str = "location_presets.json";
+// ICMP and IPv4 headers on a probe, and IPv4, UDP and WireGuard headers inside the tunnel
+const PING_OVERHEAD: u32 = 28;
+const WIREGUARD_OVERHEAD: u32 = 60;
+const GEOIP_CACHE_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// Exit status when the working directory can't be written, so scripts can tell it apart from API failures
+const EXIT_NOT_WRITABLE: i32 = 3;
+
+// Set from --json-diagnostics before any work starts
+static JSON_DIAGNOSTICS: AtomicBool = AtomicBool::new(false);
+
+const MISSING_SCOPE: &str = "Access token is not valid for WireGuard configuration: it cannot read service credentials. \
+Generate a new access token under Nord Account > NordVPN > Set up NordVPN manually and try again.";
+
+/// Generate NordVPN WireGuard configs sorted by server load and distance
+#[derive(Parser)]
+#[command(version, about)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Also write a systemd unit next to each best config that brings it up as this interface (e.g. wg0)
+    #[arg(long, value_name = "IFACE", value_parser = parse_interface)]
+    emit_service: Option<String>,
+
+    /// Skip servers whose load is missing, zero or above 100 instead of treating a missing load as 100
+    #[arg(long)]
+    strict_load: bool,
+
+    /// Print the first N configs (in sort order) to stderr with the private key redacted
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    preview: usize,
+
+    /// Show the real private key in --preview output
+    #[arg(long, requires = "preview")]
+    show_key: bool,
+
+    /// Only fetch servers in this group (e.g. legacy_p2p, see the groups subcommand)
+    #[arg(long, value_name = "IDENTIFIER")]
+    group: Option<String>,
+
+    /// Skip servers at virtual locations (physically hosted in another country)
+    #[arg(long, conflicts_with = "only_virtual")]
+    no_virtual: bool,
+
+    /// Only generate configs for servers at virtual locations
+    #[arg(long)]
+    only_virtual: bool,
+
+    /// Write servers.json without indentation to keep it small
+    #[arg(long)]
+    compact_json: bool,
+
+    /// Only write fastest.conf for the single server with the best combined load and distance score
+    #[arg(long)]
+    fastest: bool,
+
+    /// Weight of server load (as a fraction of 100%) in the --fastest score
+    #[arg(long, default_value_t = 0.7, requires = "fastest")]
+    weight_load: f64,
+
+    /// Weight of distance (as a fraction of the farthest server) in the --fastest score
+    #[arg(long, default_value_t = 0.3, requires = "fastest")]
+    weight_distance: f64,
+
+    /// Keep the order the API returned servers in instead of sorting by load and distance
+    #[arg(long)]
+    no_sort: bool,
+
+    /// Only write the N lowest-load servers of each continent to top_per_continent/
+    #[arg(long, value_name = "N", conflicts_with = "fastest")]
+    top_per_continent: Option<usize>,
+
+    /// Write the --fastest config to this exact path instead of fastest.conf
+    #[arg(long, value_name = "PATH", requires = "fastest")]
+    out_file: Option<PathBuf>,
+
+    /// How to organize the configs folder
+    #[arg(long, value_enum, default_value_t = GroupBy::Location)]
+    group_by: GroupBy,
+
+    /// With --group-by=load, servers at or above this load go in medium/
+    #[arg(long, default_value_t = 30.0)]
+    medium_load: f64,
+
+    /// With --group-by=load, servers at or above this load go in high/
+    #[arg(long, default_value_t = 70.0)]
+    high_load: f64,
+
+    /// Use this host:port as the endpoint of every config instead of the server's own address
+    #[arg(long, value_name = "HOST:PORT", value_parser = parse_endpoint)]
+    endpoint_override: Option<String>,
+
+    /// PersistentKeepalive interval, as seconds or a duration like 25s or 1m (15-120 seconds)
+    #[arg(long, value_name = "DURATION", default_value = "25", value_parser = parse_keepalive)]
+    keepalive: i32,
+
+    /// Only check that the token works and the API is reachable, without writing anything
+    #[arg(long)]
+    dry_validate: bool,
+
+    /// Print the elapsed time as raw seconds even on a terminal
+    #[arg(long)]
+    machine_time: bool,
+
+    /// Take over the output lock even if another run appears to hold it
+    #[arg(long)]
+    force: bool,
+
+    /// Drop cities that have fewer than N servers left after filtering
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    min_servers_per_city: usize,
+
+    /// Which station address the Endpoint uses
+    #[arg(long, value_enum, default_value_t = EndpointFamily::Ipv4)]
+    endpoint_family: EndpointFamily,
+
+    /// Save the raw server list to FILE, and the credentials response with secrets redacted next to it
+    #[arg(long, value_name = "FILE")]
+    dump_api: Option<PathBuf>,
+
+    /// Shell command to run after a fully successful run, with {dir} replaced by the output directory
+    #[arg(long, value_name = "COMMAND")]
+    post_run: Option<String>,
+
+    /// Also write failover_configs/<country>.conf listing the N best servers of each country as peers
+    #[arg(long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    failover: Option<usize>,
+
+    /// Also write a base64-encoded copy of every config as <name>.conf.b64
+    #[arg(long)]
+    base64: bool,
+
+    /// Only write the full per-server tree for these countries (names or ISO codes); others just get best configs
+    #[arg(long, value_name = "COUNTRIES", value_delimiter = ',')]
+    full_country: Vec<String>,
+
+    /// Generate every location without showing the interactive country/city menu
+    #[arg(long)]
+    all: bool,
+
+    /// Print why each best config's server was picked
+    #[arg(long)]
+    explain: bool,
+
+    /// Only generate N random servers, spread over as many countries as possible
+    #[arg(long, value_name = "N")]
+    sample: Option<usize>,
+
+    /// Seed for --sample so the same subset can be drawn again
+    #[arg(long, requires = "sample")]
+    seed: Option<u64>,
+
+    /// Resolve each server's hostname and warn when it doesn't point at the station address
+    #[arg(long)]
+    validate_endpoints: bool,
+
+    /// With --validate-endpoints, leave out servers whose hostname resolves elsewhere
+    #[arg(long, requires = "validate_endpoints")]
+    skip_mismatched: bool,
+
+    /// Add an FwMark line to every config, as decimal or 0x-prefixed hex
+    #[arg(long, value_parser = parse_fwmark)]
+    fwmark: Option<u32>,
+
+    /// Search domains to append to the DNS line, comma-separated (e.g. corp.example)
+    #[arg(long, value_name = "DOMAINS", value_delimiter = ',', value_parser = parse_domain)]
+    dns_search: Vec<String>,
+
+    /// Write an explicit SaveConfig line so wg-quick never rewrites the config on shutdown (true or false)
+    #[arg(long, value_name = "BOOL")]
+    save_config: Option<bool>,
+
+    /// Only route this country's IP ranges (ISO code) through the tunnel instead of all traffic
+    #[arg(long, value_name = "CODE", value_parser = parse_country_code)]
+    route_country: Option<String>,
+
+    /// Write wg-quick configs, or export the selected servers as one Nix or Ansible vars file instead
+    #[arg(long, value_enum, default_value_t = OutputFormat::WgQuick)]
+    format: OutputFormat,
+
+    /// Leave the private key out of --format exports
+    #[arg(long)]
+    redact_keys: bool,
+
+    /// Also store each server's endpoint, public key and coordinates in servers.json for --from-server-info
+    #[arg(long)]
+    full_server_info: bool,
+
+    /// Rebuild configs from a servers.json written with --full-server-info instead of fetching the server list
+    #[arg(long, value_name = "FILE", conflicts_with = "group")]
+    from_server_info: Option<PathBuf>,
+
+    /// Print each country's server count and min/median load after the filters, then exit without a token
+    #[arg(long)]
+    count_per_country: bool,
+
+    /// Name configs 01.conf, 02.conf, ... in sort order, with the server name in a comment at the top
+    #[arg(long, conflicts_with = "group_by")]
+    rank_filenames: bool,
+
+    /// Pick each city's best config from NordVPN's recommendations instead of the lowest load
+    #[arg(long, conflicts_with = "from_server_info")]
+    use_api_score: bool,
+
+    /// Skip the ipinfo.io location lookup and sort purely by load
+    #[arg(long)]
+    no_distance: bool,
+
+    /// Also write the server's hostname as a commented-out alternative Endpoint
+    #[arg(long, conflicts_with = "endpoint_override")]
+    endpoint_both: bool,
+
+    /// Write at most N config files at a time, for SD cards and network filesystems
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    io_throttle: Option<u32>,
+
+    /// Also write best_per_country/<country>.conf with the lowest-load city pick of each country
+    #[arg(long)]
+    best_per_country: bool,
+
+    /// Use a location saved with --save-location-preset instead of looking it up
+    #[arg(long, value_name = "NAME", conflicts_with = "no_distance")]
+    location_preset: Option<String>,
+
+    /// Look up the current location, save it under NAME in location_presets.json and exit
+    #[arg(long, value_name = "NAME")]
+    save_location_preset: Option<String>,
+
+    /// Print warnings and errors on stderr as one JSON object per line with a stable code
+    #[arg(long)]
+    json_diagnostics: bool,
+
+    /// Only regenerate this country's configs, leaving files of other countries untouched
+    #[arg(long, value_name = "COUNTRY", conflicts_with_all = ["full_country", "group_by", "rank_filenames"])]
+    update_country: Option<String>,
+
+    /// Measure the path MTU to the N lowest-load best servers and write it into their best configs (Linux)
+    #[arg(long, value_name = "N")]
+    probe_mtu: Option<usize>,
+
+    /// Label matching servers, e.g. country:us=home or group:legacy_p2p=torrent (fields: country, city, group, server)
+    #[arg(long, value_name = "FIELD:VALUE=LABEL", value_parser = parse_label)]
+    label: Vec<LabelRule>,
+
+    /// NordVPN access token, instead of typing it at the prompt
+    #[arg(long, env = "NORDVPN_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// Never prompt: fail when the token is missing and skip the country/city menu
+    #[arg(long)]
+    non_interactive: bool,
+
+    /// DNS servers for the DNS line, comma-separated IPv4 or IPv6 addresses (default 103.86.96.100)
+    #[arg(long, value_name = "IPS", value_parser = parse_dns)]
+    dns: Option<DnsServers>,
+
+    /// Use the station IP as Endpoint (true) or the server's hostname (false)
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = ArgAction::Set)]
+    use_ip: bool,
+
+    /// Write all output (configs, servers.json, ...) into this folder instead of the current one
+    #[arg(long, value_name = "DIR")]
+    output_dir: Option<PathBuf>,
+
+    /// Only keep servers in these countries, comma-separated names or ISO codes (e.g. us,de)
+    #[arg(long, value_name = "COUNTRIES", value_delimiter = ',')]
+    country: Vec<String>,
+
+    /// Only keep servers in these cities, comma-separated (e.g. "new york")
+    #[arg(long, value_name = "CITIES", value_delimiter = ',')]
+    city: Vec<String>,
+
+    /// Drop servers whose load is above this percentage
+    #[arg(long, value_name = "PERCENT")]
+    max_load: Option<f64>,
+
+    /// Keep only the N lowest-load servers of each city
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    top_n: Option<u32>,
+
+    #[command(flatten)]
+    amnezia: AmneziaParams,
+
+    /// LOCATION_PRESETS in the directory the tool was started from, so --output-dir doesn't move it
+    #[arg(skip)]
+    presets_file: PathBuf,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum OutputFormat {
+    /// One .conf file per server
+    WgQuick,
+    /// servers.nix attribute set
+    Nix,
+    /// servers.yml Ansible variables
+    AnsibleVars,
+    /// One sorted KEY='value' .env file per server
+    Env,
+    /// wg-quick configs with AmneziaWG obfuscation settings (see the --awg-* flags)
+    Amneziawg,
+}
+
+/// AmneziaWG [Interface] settings. NordVPN runs plain WireGuard, so only the junk packets (Jc, Jmin, Jmax)
+/// may differ from the defaults; S1, S2 and H1-H4 have to match the server and are exposed for other setups.
+#[derive(clap::Args, Clone, Copy)]
+struct AmneziaParams {
+    /// AmneziaWG: number of junk packets sent before the handshake
+    #[arg(long = "awg-jc", value_name = "N", default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..=128))]
+    jc: u32,
+    /// AmneziaWG: minimum junk packet size in bytes
+    #[arg(long = "awg-jmin", value_name = "BYTES", default_value_t = 40)]
+    jmin: u32,
+    /// AmneziaWG: maximum junk packet size in bytes (at most 1280)
+    #[arg(long = "awg-jmax", value_name = "BYTES", default_value_t = 70, value_parser = clap::value_parser!(u32).range(1..=1280))]
+    jmax: u32,
+    /// AmneziaWG: junk bytes prepended to the handshake initiation
+    #[arg(long = "awg-s1", value_name = "BYTES", default_value_t = 0)]
+    s1: u32,
+    /// AmneziaWG: junk bytes prepended to the handshake response
+    #[arg(long = "awg-s2", value_name = "BYTES", default_value_t = 0)]
+    s2: u32,
+    /// AmneziaWG: message type header of handshake initiations
+    #[arg(long = "awg-h1", value_name = "VALUE", default_value_t = 1)]
+    h1: u32,
+    /// AmneziaWG: message type header of handshake responses
+    #[arg(long = "awg-h2", value_name = "VALUE", default_value_t = 2)]
+    h2: u32,
+    /// AmneziaWG: message type header of cookie replies
+    #[arg(long = "awg-h3", value_name = "VALUE", default_value_t = 3)]
+    h3: u32,
+    /// AmneziaWG: message type header of transport data
+    #[arg(long = "awg-h4", value_name = "VALUE", default_value_t = 4)]
+    h4: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum GroupBy {
+    /// configs/<country>/<city>/
+    Location,
+    /// configs/low/, configs/medium/ and configs/high/
+    Load,
+    /// configs/<first --label that matches>/, or configs/unlabeled/
+    Label,
+}
+
+const DEFAULT_DNS: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(103, 86, 96, 100));
+
+/// The --dns list, parsed as a whole so an empty list can mean "use the default"
+#[derive(Clone)]
+struct DnsServers(Vec<IpAddr>);
+
+const LABEL_FIELDS: [&str; 4] = ["country", "city", "group", "server"];
+
+/// A --label rule: servers whose FIELD matches VALUE get LABEL
+#[derive(Clone)]
+struct LabelRule {
+    field: String,
+    value: String,
+    label: String,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the server groups NordVPN exposes (P2P, obfuscated, regions, ...)
+    Groups,
+    /// Repeatedly print the lowest-load server in a country without writing any files
+    Monitor {
+        /// Country name or ISO code
+        country: String,
+        /// Seconds between catalog fetches (at least 30 to stay clear of API rate limits)
+        #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(30..))]
+        interval: u64,
+    },
+    /// List the cities of a country with their WireGuard server counts
+    ListCities {
+        /// Country name or ISO code
+        country: String,
+        /// Order by server count (largest first) or by name
+        #[arg(long, value_enum, default_value_t = CitySort::Count)]
+        sort: CitySort,
+    },
+    /// Print the version and supported output formats as JSON, for wrappers and packaging
+    Info,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum CitySort {
+    Count,
+    Name,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum EndpointFamily {
+    /// The IPv4 station
+    Ipv4,
+    /// The IPv6 station, skipping servers that have none
+    Ipv6,
+    /// The IPv6 station when the server has one, otherwise IPv4
+    Auto,
+}
+
+#[derive(Clone, Copy)]
+enum Level {
+    Warn,
+    Error,
+}
+
+/// Outcome of a generation run, used for the closing summary
+struct RunReport {
+    configs_written: usize,
+    /// Server name and error for every config that failed to save
+    errors: Vec<(String, String)>,
+    /// Names of the servers picked for best_configs
+    best_servers: Vec<String>,
+    elapsed: Duration,
+    /// How long each API request took, in the order they were made
+    api_timings: Vec<(&'static str, Duration)>,
+    output_dir: PathBuf,
+    /// Whether any config or export was written or removed, so --post-run has something to act on
+    wrote_output: bool,
+}
+
+/// Settings applied to every generated config
+struct UserConfig {
+    endpoint_override: Option<String>,
+    endpoint_family: EndpointFamily,
+    keepalive: i32,
+    base64: bool,
+    fwmark: Option<u32>,
+    dns: Vec<IpAddr>,
+    dns_search: Vec<String>,
+    use_ip: bool,
+    save_config: Option<bool>,
+    allowed_ips: String,
+    /// AllowedIPs holds --route-country ranges rather than everything
+    country_routes: bool,
+    /// Start each config with a comment naming its server
+    name_comment: bool,
+    endpoint_both: bool,
+    /// Leave files whose contents would not change alone, so watchers don't see a write
+    only_changed: bool,
+    /// WgQuick, Env or Amneziawg; the single-file exports never reach generate_config
+    format: OutputFormat,
+    amnezia: AmneziaParams,
+}
+
+impl UserConfig {
+    // The flag parsers check each value, this catches combinations that still can't produce a working config
+    fn is_valid(&self) -> bool {
+        (15..=120).contains(&self.keepalive) && !self.dns.is_empty() && !self.allowed_ips.is_empty() && self.amnezia.jmin <= self.amnezia.jmax
+    }
+}
+
+fn parse_country_code(value: &str) -> Result<String, String> {
+    if value.len() == 2 && value.chars().all(|c| c.is_ascii_alphabetic()) {
+        Ok(value.to_ascii_lowercase())
+    } else {
+        Err("expected a two-letter ISO country code such as US".to_string())
+    }
+}
+
+fn parse_domain(value: &str) -> Result<String, String> {
+    let domain = value.trim().trim_end_matches('.');
+    let valid_label = |label: &str| {
+        (1..=63).contains(&label.len())
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+    };
+    // Anything that parses as an address would be taken as a resolver by wg-quick, not a search domain
+    if domain.len() <= 253 && domain.split('.').all(valid_label) && domain.parse::<std::net::IpAddr>().is_err() {
+        Ok(domain.to_string())
+    } else {
+        Err(format!("'{}' is not a valid search domain", value))
+    }
+}
+
+fn parse_label(rule: &str) -> Result<LabelRule, String> {
+    let usage = "expected FIELD:VALUE=LABEL, e.g. country:us=home";
+    let (matcher, label) = rule.split_once('=').ok_or(usage)?;
+    let (field, value) = matcher.split_once(':').ok_or(usage)?;
+    if !LABEL_FIELDS.contains(&field) {
+        return Err(format!("unknown field {}, expected one of {}", field, LABEL_FIELDS.join(", ")));
+    }
+    // The label can become a folder name with --group-by label
+    if value.is_empty() || label.is_empty() || label.contains(['/', '\\']) || label.starts_with('.') {
+        return Err(usage.to_string());
+    }
+    Ok(LabelRule { field: field.to_string(), value: value.to_string(), label: label.to_string() })
+}
+
+fn parse_dns(list: &str) -> Result<DnsServers, String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| entry.parse().map_err(|_| format!("{} is not an IPv4 or IPv6 address", entry)))
+        .collect::<Result<_, _>>()
+        .map(DnsServers)
+}
+
+fn parse_fwmark(value: &str) -> Result<u32, String> {
+    let parsed = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse::<u32>(),
+    };
+    parsed.map_err(|_| format!("'{}' is not a valid 32-bit fwmark", value))
+}
+
+fn parse_keepalive(value: &str) -> Result<i32, String> {
+    let (number, scale) = match value.strip_suffix('m') {
+        Some(minutes) => (minutes, 60),
+        None => (value.strip_suffix('s').unwrap_or(value), 1),
+    };
+    let seconds = number.trim().parse::<i32>().ok().and_then(|n| n.checked_mul(scale))
+        .ok_or("expected seconds or a duration like 25s or 1m")?;
+    if (15..=120).contains(&seconds) {
+        Ok(seconds)
+    } else {
+        Err(format!("keepalive must be between 15 and 120 seconds, got {}", seconds))
+    }
+}
+
+/// Lock file marking the output folders as in use, removed again when dropped
+struct OutputLock(PathBuf);
+
+impl OutputLock {
+    // create_new makes taking the lock atomic, so two runs starting together can't both get it
+    fn acquire(path: &Path, force: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        let create = || std::fs::OpenOptions::new().write(true).create_new(true).open(path);
+        let mut file = match create() {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let pid = std::fs::read_to_string(path).unwrap_or_default();
+                let pid = pid.trim();
+                if !force && process_alive(pid) {
+                    return Err(format!("Another run (PID {}) is writing to this directory. Pass --force if {} is stale.", pid, path.display()).into());
+                }
+                // Stale or forced: replace the file, still failing if another run replaced it first
+                std::fs::remove_file(path)?;
+                create()?
+            }
+            Err(e) => return Err(e.into()),
+        };
+        file.write_all(std::process::id().to_string().as_bytes())?;
+        Ok(OutputLock(path.to_path_buf()))
+    }
+}
+
+impl Drop for OutputLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+// Access tokens from Nord Account are 64 hex characters; catching typos here saves an API round trip
+fn is_valid_token(token: &str) -> bool {
+    token.len() == 64 && token.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Permission problems get their own message and exit status; other errors are left to the caller
+fn exit_if_not_writable(dir: &Path, e: &io::Error) {
+    if matches!(e.kind(), io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem) {
+        diagnostic(Level::Error, "output_not_writable", None, &format!("Cannot write configs to {}: {}. Pass --output-dir with a folder you can write to.", dir.display(), e));
+        std::process::exit(EXIT_NOT_WRITABLE);
+    }
+}
+
+// Only Linux exposes a process table we can check cheaply, elsewhere any lock is assumed live
+fn process_alive(pid: &str) -> bool {
+    if cfg!(target_os = "linux") {
+        pid.parse::<u32>().is_ok() && Path::new("/proc").join(pid).exists()
+    } else {
+        true
+    }
+}
+
+fn parse_endpoint(endpoint: &str) -> Result<String, String> {
+    let (host, port) = endpoint.rsplit_once(':').ok_or("expected HOST:PORT")?;
+    let port_valid = port.parse::<u16>().is_ok_and(|p| p != 0);
+    let host_valid = match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.strip_suffix(']').is_some_and(|ip| ip.parse::<std::net::Ipv6Addr>().is_ok()),
+        None => !host.is_empty() && !host.contains(':'),
+    };
+    if host_valid && port_valid {
+        Ok(endpoint.to_string())
+    } else {
+        Err("expected HOST:PORT with a port between 1 and 65535 (wrap IPv6 hosts in brackets)".to_string())
+    }
+}
+
+fn parse_interface(name: &str) -> Result<String, String> {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || "_=+.-".contains(c);
+    if !name.is_empty() && name.len() <= 15 && name.chars().all(valid_char) {
+        Ok(name.to_string())
+    } else {
+        Err("interface names must be 1-15 characters of letters, digits and _=+.-".to_string())
+    }
+}
+
+// Other statuses, like the 401 of a bad token, are returned at once for the caller to interpret
+async fn send_with_retry(request: RequestBuilder, service: &str) -> Result<Response, Box<dyn std::error::Error>> {
+    let mut attempt = 1;
+    loop {
+        let result = request.try_clone().ok_or("request cannot be retried")?.send().await;
+        let reason = match &result {
+            Ok(res) if res.status().is_server_error() => res.status().to_string(),
+            Err(e) if e.is_connect() || e.is_timeout() || e.is_request() => e.to_string(),
+            _ => return Ok(result?),
+        };
+        if attempt == API_RETRY_ATTEMPTS {
+            return Err(format!("Failed to reach {} after {} attempts: {}", service, attempt, reason).into());
+        }
+        let delay = API_RETRY_DELAY * 2u32.pow(attempt - 1);
+        diagnostic(Level::Warn, "api_retry", None, &format!("{} request failed ({}), retrying in {}s ({}/{})...", service, reason, delay.as_secs(), attempt, API_RETRY_ATTEMPTS));
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+pub async fn get_key(client: &Client, token: &str, dump: Option<&Path>) -> Result<String, Box<dyn std::error::Error>> {
+    let request = client
         .get("https://api.nordvpn.com/v1/users/services/credentials")
-        .basic_auth("token", Some(token))
-        .send()
-        .await?;
+        .basic_auth("token", Some(token));
+    let res = send_with_retry(request, "NordVPN API").await?;
+
+    let status = res.status();
+    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        let body = res.text().await.unwrap_or_default().to_lowercase();
+        if status == StatusCode::FORBIDDEN || ["scope", "permission", "forbidden"].iter().any(|hint| body.contains(hint)) {
+            return Err(MISSING_SCOPE.into());
+        }
+        return Err("Access token is not valid".into());
+    }
+
+    let body = res.text().await?;
+    let v: Value = serde_json::from_str(&body)?;
+
+    if let Some(path) = dump {
+        let mut redacted = v.clone();
+        for field in ["nordlynx_private_key", "username", "password"] {
+            if let Some(value) = redacted.get_mut(field) {
+                *value = json!("<redacted>");
+            }
+        }
+        fs::write(path, serde_json::to_string_pretty(&redacted)?).await?;
+    }
+
+    let private_key = match v.get("nordlynx_private_key") {
+        Some(private_key) => private_key.as_str().unwrap_or_default().to_string(),
+        None => return Err(MISSING_SCOPE.into()),
+    };
+    // A malformed key would otherwise end up in every config and only fail when the tunnel comes up
+    if !is_valid_key(&private_key) {
+        return Err("NordVPN returned a private key that is not 32 bytes of base64; refusing to write configs with it".into());
+    }
+    Ok(private_key)
+}
+
+pub async fn get_servers(client: &Client, limit: usize, group: Option<&str>, dump: Option<&Path>) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+    let url = format!("https://api.nordvpn.com/v1/servers?limit={}&filters[servers_technologies][identifier]=wireguard_udp", limit);
+    let mut request = client.get(url);
+    if let Some(group) = group {
+        // Encoded by reqwest, so a typo like "p2p&limit=1" can't add or cut off parameters
+        request = request.query(&[("filters[servers_groups][identifier]", group)]);
+    }
+    let res = send_with_retry(request, "NordVPN API").await?;
+    let status = res.status();
+    let body = res.text().await?;
+    // Written before parsing so schema changes that break deserialization can still be reproduced
+    if let Some(path) = dump {
+        fs::write(path, &body).await?;
+    }
+    if !status.is_success() {
+        return Err(format!("NordVPN API answered {}", status).into());
+    }
+    let servers: Vec<Value> = serde_json::from_str(&body)?;
+    Ok(servers)
+}
+
+// Names of the WireGuard servers NordVPN recommends in a country, best first
+async fn get_recommendations(client: &Client, country_id: u64) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    tokio::time::sleep(RECOMMENDATION_DELAY).await;
+    let url = format!(
+        "https://api.nordvpn.com/v1/servers/recommendations?limit={}&filters[servers_technologies][identifier]=wireguard_udp&filters[country_id]={}",
+        RECOMMENDATION_LIMIT, country_id
+    );
+    let res = client.get(url).send().await?.error_for_status()?;
+    let servers: Vec<Value> = res.json().await?;
+    Ok(servers.iter().filter_map(|server| server["name"].as_str().map(str::to_string)).collect())
+}
+
+// Codes are part of the --json-diagnostics output, so existing ones must not be renamed
+fn diagnostic(level: Level, code: &str, server: Option<&str>, message: &str) {
+    if JSON_DIAGNOSTICS.load(AtomicOrdering::Relaxed) {
+        let level = match level {
+            Level::Warn => "warn",
+            Level::Error => "error",
+        };
+        let mut line = json!({ "level": level, "code": code, "message": message });
+        if let Some(server) = server {
+            line["server"] = json!(server);
+        }
+        eprintln!("{}", line);
+    } else {
+        match level {
+            Level::Warn => eprintln!("Warning: {}", message),
+            Level::Error => eprintln!("{}", message),
+        }
+    }
+}
+
+// Aggregated per-country ranges from ipdeny.com, cached for a week since they change slowly
+async fn get_country_ranges(client: &Client, code: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let sources = [
+        ("ipv4", format!("https://www.ipdeny.com/ipblocks/data/aggregated/{}-aggregated.zone", code)),
+        ("ipv6", format!("https://www.ipdeny.com/ipv6/ipaddresses/aggregated/{}-aggregated.zone", code)),
+    ];
+    let mut ranges = Vec::new();
+    for (family, url) in sources {
+        let cache = Path::new(GEOIP_CACHE_DIR).join(format!("{}-{}.zone", code, family));
+        let fresh = std::fs::metadata(&cache).and_then(|m| m.modified()).ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age < GEOIP_CACHE_AGE);
+        let zone = if fresh {
+            fs::read_to_string(&cache).await?
+        } else {
+            let res = send_with_retry(client.get(&url), "ipdeny.com").await?;
+            if !res.status().is_success() {
+                // Some countries have no IPv6 allocations and ipdeny has no file for them
+                if family == "ipv6" {
+                    continue;
+                }
+                return Err(format!("No IP ranges available for country {} ({})", code.to_uppercase(), res.status()).into());
+            }
+            let zone = res.text().await?;
+            fs::create_dir_all(GEOIP_CACHE_DIR).await?;
+            fs::write(&cache, &zone).await?;
+            zone
+        };
+        ranges.extend(zone.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')).map(str::to_string));
+    }
+    Ok(ranges)
+}
+
+pub async fn get_groups(client: &Client) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+    let res = client.get("https://api.nordvpn.com/v1/servers/groups").send().await?;
+    let groups: Vec<Value> = res.json().await?;
+    Ok(groups)
+}
+
+pub fn find_key(server: &Value) -> Option<String> {
+    if let Some(technologies) = server.get("technologies")?.as_array() {
+        for tech in technologies {
+            if tech.get("identifier")?.as_str()? == "wireguard_udp" {
+                if let Some(metadata) = tech.get("metadata")?.as_array() {
+                    for data in metadata {
+                        if data.get("name")?.as_str()? == "public_key" {
+                            return data.get("value")?.as_str().map(|s| s.to_string());
+                        }
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+// The fields --from-server-info needs to turn a servers.json entry back into a server
+fn server_details(server: &Value) -> Value {
+    let location = &server["locations"][0];
+    json!({
+        "hostname": server["hostname"],
+        "station": server["station"],
+        "ipv6_station": server["ipv6_station"],
+        "public_key": find_key(server),
+        "country_code": location["country"]["code"],
+        "latitude": location["latitude"],
+        "longitude": location["longitude"],
+    })
+}
+
+// Rebuilds the parts of the API server object that config generation reads
+fn server_from_info(country: &str, city: &str, entry: &Value) -> Option<Value> {
+    // Entries can carry just labels, which isn't enough to rebuild a config
+    let details = entry.get(2).filter(|details| details["public_key"].is_string())?;
+    Some(json!({
+        "name": entry[0],
+        "load": entry[1],
+        "hostname": details["hostname"],
+        "station": details["station"],
+        "ipv6_station": details["ipv6_station"],
+        "locations": [{
+            "latitude": details["latitude"],
+            "longitude": details["longitude"],
+            "country": { "name": country, "code": details["country_code"], "city": { "name": city } },
+        }],
+        "technologies": [{
+            "identifier": "wireguard_udp",
+            "metadata": [{ "name": "public_key", "value": details["public_key"] }],
+        }],
+    }))
+}
+
+async fn read_server_info(path: &Path) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+    let info: BTreeMap<String, BTreeMap<String, Vec<Value>>> = serde_json::from_str(&fs::read_to_string(path).await?)?;
+    let mut servers = Vec::new();
+    for (country, cities) in &info {
+        for (city, entries) in cities {
+            for entry in entries {
+                let server = server_from_info(country, city, entry)
+                    .ok_or_else(|| format!("{} has no server details; regenerate it with --full-server-info", path.display()))?;
+                servers.push(server);
+            }
+        }
+    }
+    Ok(servers)
+}
+
+fn in_group(server: &Value, group: &str) -> bool {
+    server["groups"].as_array().is_some_and(|groups| groups.iter().any(|g| g["identifier"] == group))
+}
+
+// None when the server carries no virtual_location specification, so callers can tell "physical" from "unknown"
+fn is_virtual(server: &Value) -> Option<bool> {
+    let spec = server["specifications"].as_array()?.iter().find(|spec| spec["identifier"] == "virtual_location")?;
+    Some(spec["values"].as_array().is_some_and(|values| values.iter().any(|v| v["value"] == "true")))
+}
+
+// WireGuard keys are 32 raw bytes, anything else fails at connect time with an unhelpful error
+fn is_valid_key(key: &str) -> bool {
+    STANDARD.decode(key).is_ok_and(|key| key.len() == 32)
+}
+
+fn format_name(name: &str) -> String {
+    let name = name.replace(" ", "_");
+    let name = name.replace("-", "");
+    name.replace("__", "_")
+}
+
+fn country_city(server: &Value) -> (&str, &str) {
+    let country = &server["locations"][0]["country"];
+    (country["name"].as_str().unwrap(), country["city"]["name"].as_str().unwrap_or("Unknown"))
+}
+
+// Case, surrounding spaces and the underscores of folder names don't matter, so "United States", "united_states" and "us" all match
+fn same_place(a: &str, b: &str) -> bool {
+    a.trim().replace('_', " ").eq_ignore_ascii_case(&b.trim().replace('_', " "))
+}
+
+fn matches_country(server: &Value, country: &str) -> bool {
+    let location = &server["locations"][0]["country"];
+    [&location["name"], &location["code"]].iter().any(|value| value.as_str().is_some_and(|v| same_place(v, country)))
+}
+
+fn location_names(server: &Value) -> (String, String, String) {
+    let (country, city) = country_city(server);
+    let country_name = format_name(country);
+    let city_name = format_name(city);
+    let server_name = format_name(&format!("{}_{}", server["name"].as_str().unwrap().replace("#", ""), city_name));
+    (country_name, city_name, server_name)
+}
+
+fn label_matches(server: &Value, rule: &LabelRule) -> bool {
+    match rule.field.as_str() {
+        "country" => matches_country(server, &rule.value),
+        "city" => country_city(server).1.eq_ignore_ascii_case(&rule.value),
+        "group" => in_group(server, &rule.value),
+        _ => server["name"].as_str().is_some_and(|name| name.eq_ignore_ascii_case(&rule.value)),
+    }
+}
+
+// In rule order without repeats, so the first label is the one --group-by label uses
+fn server_labels<'a>(server: &Value, rules: &'a [LabelRule]) -> Vec<&'a str> {
+    let mut labels = Vec::new();
+    for rule in rules.iter().filter(|rule| label_matches(server, rule)) {
+        if !labels.contains(&rule.label.as_str()) {
+            labels.push(rule.label.as_str());
+        }
+    }
+    labels
+}
+
+fn load_bucket(load: f64, medium_load: f64, high_load: f64) -> &'static str {
+    if load >= high_load {
+        "high"
+    } else if load >= medium_load {
+        "medium"
+    } else {
+        "low"
+    }
+}
+
+fn station_address(server: &Value, family: EndpointFamily) -> Option<String> {
+    let ipv4 = server["station"].as_str().map(str::to_string);
+    let ipv6 = server["ipv6_station"].as_str().filter(|ip| !ip.is_empty()).map(|ip| format!("[{}]", ip));
+    match family {
+        EndpointFamily::Ipv4 => ipv4,
+        EndpointFamily::Ipv6 => ipv6,
+        EndpointFamily::Auto => ipv6.or(ipv4),
+    }
+}
+
+// The prefixes covering `range` except `address`; ranges that don't contain it come back unchanged
+fn exclude_address(range: &str, address: IpAddr) -> Vec<String> {
+    let parsed = range.split_once('/').and_then(|(network, len)| Some((network.parse::<IpAddr>().ok()?, len.parse::<u32>().ok()?)));
+    let (bits, network, address, len) = match (parsed, address) {
+        (Some((IpAddr::V4(network), len)), IpAddr::V4(address)) => (32, u32::from(network) as u128, u32::from(address) as u128, len),
+        (Some((IpAddr::V6(network), len)), IpAddr::V6(address)) => (128, u128::from(network), u128::from(address), len),
+        _ => return vec![range.to_string()],
+    };
+    let full = u128::MAX >> (128 - bits);
+    let mask = |len: u32| full & !full.checked_shr(len).unwrap_or(0);
+    if len > bits || address & mask(len) != network & mask(len) {
+        return vec![range.to_string()];
+    }
+    // Walking down to the address, each level keeps the half it doesn't fall into
+    (len + 1..=bits).map(|len| {
+        let sibling = (address & mask(len)) ^ (1 << (bits - len));
+        let sibling = if bits == 32 { IpAddr::from(std::net::Ipv4Addr::from(sibling as u32)) } else { IpAddr::from(std::net::Ipv6Addr::from(sibling)) };
+        format!("{}/{}", sibling, len)
+    }).collect()
+}
+
+// wg-quick only keeps the handshake out of the tunnel for /0 routes, so a server inside the
+// routed country needs its own address cut out of the ranges or it would tunnel to itself
+fn server_allowed_ips(server: &Value, user_config: &UserConfig) -> String {
+    if !user_config.country_routes {
+        return user_config.allowed_ips.clone();
+    }
+    let override_address = user_config.endpoint_override.as_ref().and_then(|endpoint| endpoint.parse::<std::net::SocketAddr>().ok()).map(|endpoint| endpoint.ip());
+    let addresses = [server["station"].as_str(), server["ipv6_station"].as_str()].into_iter().flatten()
+        .filter_map(|address| address.parse::<IpAddr>().ok())
+        .chain(override_address)
+        .collect::<Vec<_>>();
+    let mut ranges = user_config.allowed_ips.split(", ").map(str::to_string).collect::<Vec<_>>();
+    for address in addresses {
+        ranges = ranges.iter().flat_map(|range| exclude_address(range, address)).collect();
+    }
+    ranges.join(", ")
+}
+
+fn dns_servers(user_config: &UserConfig) -> String {
+    user_config.dns.iter().map(IpAddr::to_string).chain(user_config.dns_search.iter().cloned()).collect::<Vec<_>>().join(", ")
+}
+
+fn interface_block(key: &str, user_config: &UserConfig) -> String {
+    let mut interface = format!("[Interface]
+PrivateKey = {}
+Address = 10.5.0.2/16
+DNS = {}
+", key, dns_servers(user_config));
+    if let Some(fwmark) = user_config.fwmark {
+        interface.push_str(&format!("FwMark = {:#x}\n", fwmark));
+    }
+    if let Some(save_config) = user_config.save_config {
+        interface.push_str(&format!("SaveConfig = {}\n", save_config));
+    }
+    if user_config.format == OutputFormat::Amneziawg {
+        let awg = user_config.amnezia;
+        interface.push_str(&format!(
+            "Jc = {}\nJmin = {}\nJmax = {}\nS1 = {}\nS2 = {}\nH1 = {}\nH2 = {}\nH3 = {}\nH4 = {}\n",
+            awg.jc, awg.jmin, awg.jmax, awg.s1, awg.s2, awg.h1, awg.h2, awg.h3, awg.h4
+        ));
+    }
+    interface
+}
+
+fn server_endpoint(server: &Value, user_config: &UserConfig) -> Option<String> {
+    match &user_config.endpoint_override {
+        Some(endpoint) => Some(endpoint.clone()),
+        None if !user_config.use_ip => server["hostname"].as_str().map(|hostname| format!("{}:51820", hostname)),
+        None => station_address(server, user_config.endpoint_family).map(|address| format!("{}:51820", address)),
+    }
+}
+
+fn endpoint_or_skip(server: &Value, user_config: &UserConfig) -> Option<String> {
+    let endpoint = server_endpoint(server, user_config);
+    if endpoint.is_none() {
+        let name = server["name"].as_str().unwrap();
+        diagnostic(Level::Warn, "skipped_server", Some(name), &format!("no matching station address found for {}, skipping.", name));
+    }
+    endpoint
+}
+
+fn peer_block(server: &Value, public_key: &str, user_config: &UserConfig) -> Option<String> {
+    let endpoint = endpoint_or_skip(server, user_config)?;
+    // The hostname keeps working if NordVPN renumbers the server, the station avoids a DNS lookup
+    let alternative = if user_config.use_ip {
+        server["hostname"].as_str().map(str::to_string)
+    } else {
+        station_address(server, user_config.endpoint_family)
+    };
+    let alternative = match alternative {
+        Some(address) if user_config.endpoint_both => format!("# Endpoint (alt) = {}:51820\n", address),
+        _ => String::new(),
+    };
+    Some(format!("[Peer]
+PublicKey = {}
+AllowedIPs = {}
+Endpoint = {}
+{}PersistentKeepalive = {}
+", public_key, server_allowed_ips(server, user_config), endpoint, alternative, user_config.keepalive))
+}
+
+// Sorted KEY='value' lines diff cleanly in git and can be sourced by a shell
+fn env_config(key: &str, server: &Value, public_key: &str, user_config: &UserConfig) -> Option<String> {
+    let mut vars = BTreeMap::from([
+        ("ADDRESS", "10.5.0.2/16".to_string()),
+        ("ALLOWED_IPS", server_allowed_ips(server, user_config)),
+        ("DNS", dns_servers(user_config)),
+        ("ENDPOINT", endpoint_or_skip(server, user_config)?),
+        ("KEEPALIVE", user_config.keepalive.to_string()),
+        ("PRIVATE_KEY", key.to_string()),
+        ("PUBLIC_KEY", public_key.to_string()),
+    ]);
+    if let Some(fwmark) = user_config.fwmark {
+        vars.insert("FWMARK", format!("{:#x}", fwmark));
+    }
+    if let Some(save_config) = user_config.save_config {
+        vars.insert("SAVE_CONFIG", save_config.to_string());
+    }
+    Some(vars.iter().map(|(name, value)| format!("{}='{}'\n", name, value)).collect())
+}
+
+fn generate_config(key: &str, server: &Value, user_config: &UserConfig) -> Option<(String, String, String, String)> {
+    if let Some(public_key) = find_key(server) {
+        let (country_name, city_name, server_name) = location_names(server);
+        let mut config = match user_config.format {
+            OutputFormat::Env => env_config(key, server, &public_key, user_config)?,
+            _ => format!("{}\n{}", interface_block(key, user_config), peer_block(server, &public_key, user_config)?),
+        };
+        if user_config.name_comment {
+            config = format!("# {}\n{}", server["name"].as_str().unwrap(), config);
+        }
+        Some((country_name, city_name, server_name, config))
+    } else {
+        let name = server["name"].as_str().unwrap();
+        diagnostic(Level::Warn, "missing_key", Some(name), &format!("no WireGuard public key found for {} in {}, skipping.", name, country_city(server).1));
+        None
+    }
+}
+
+// Only the first peer is active; WireGuard can't route 0.0.0.0/0 to several peers, so the rest stay commented out
+fn generate_failover_config(key: &str, country: &str, servers: &[&Value], user_config: &UserConfig) -> String {
+    let mut config = interface_block(key, user_config);
+    config.push_str(&format!("
+# Failover servers for {}, best first. To switch, comment out the active [Peer]
+# block, uncomment the next one and restart the tunnel.
+", country));
+    let peers = servers.iter().filter_map(|server| {
+        let peer = peer_block(server, &find_key(server)?, user_config)?;
+        Some((server["name"].as_str().unwrap_or("Unknown"), server_load(server), peer))
+    });
+    for (index, (name, load, peer)) in peers.enumerate() {
+        config.push_str(&format!("\n# {}. {} (load {}%)\n", index + 1, name, load));
+        if index == 0 {
+            config.push_str(&peer);
+        } else {
+            config.push_str(&peer.lines().map(|line| format!("# {}\n", line)).collect::<String>());
+        }
+    }
+    config
+}
+
+// The flag is false when only_changed found the config already up to date and left it alone
+async fn save_config(key: Arc<String>, user_config: Arc<UserConfig>, server: &Value, path: Option<&str>) -> Result<Option<(String, bool)>, Box<dyn std::error::Error>> {
+    if server.get("locations").is_some() {
+        if let Some((country_folder, city_folder, server_name, config)) = generate_config(&key, server, &user_config) {
+            let extension = if user_config.format == OutputFormat::Env { "env" } else { "conf" };
+            let path = match path {
+                Some(p) => {
+                    if let Some(parent) = Path::new(p).parent() {
+                        fs::create_dir_all(parent).await?;
+                    }
+                    // Explicit paths are kept as given unless the format changes what the file holds
+                    match user_config.format {
+                        OutputFormat::Env => Path::new(p).with_extension(extension).to_str().unwrap().to_string(),
+                        _ => p.to_string(),
+                    }
+                }
+                None => {
+                    let country_path = Path::new("configs").join(&country_folder);
+                    fs::create_dir_all(&country_path).await?;
+                    let city_path = country_path.join(&city_folder);
+                    fs::create_dir_all(&city_path).await?;
+                    city_path.join(format!("{}.{}", server_name, extension)).to_str().unwrap().to_string()
+                }
+            };
+            let unchanged = |existing: io::Result<String>, contents: &str| user_config.only_changed && existing.is_ok_and(|existing| existing == contents);
+            // Checked on its own, so adding --base64 to an update run still creates the missing copies
+            if user_config.base64 {
+                let encoded_path = format!("{}.b64", path);
+                let encoded = STANDARD.encode(&config);
+                if !unchanged(fs::read_to_string(&encoded_path).await, &encoded) {
+                    fs::write(&encoded_path, encoded).await?;
+                }
+            }
+            if unchanged(fs::read_to_string(&path).await, &config) {
+                return Ok(Some((path, false)));
+            }
+            fs::write(&path, config).await?;
+            println!("WireGuard configuration for {} saved to {}", server_name, path);
+            Ok(Some((path, true)))
+        } else {
+            Ok(None)
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+// wg-quick only reads configs from /etc/wireguard/<iface>.conf, so the unit assumes the config is installed there
+fn generate_service(iface: &str, config_path: &str, description: &str) -> String {
+    format!("# Install {} as /etc/wireguard/{}.conf before enabling this unit
+[Unit]
+Description=NordVPN WireGuard tunnel to {} via {}
+After=network-online.target nss-lookup.target
+Wants=network-online.target nss-lookup.target
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStart=/usr/bin/wg-quick up {}
+ExecStop=/usr/bin/wg-quick down {}
+
+[Install]
+WantedBy=multi-user.target
+", config_path, iface, description, iface, iface, iface)
+}
+
+// Servers without a load are assumed fully loaded so they sort last rather than first
+fn server_load(server: &Value) -> f64 {
+    server["load"].as_f64().unwrap_or(100.0)
+}
+
+// servers.json has always held loads as 12, not 12.0, and scripts read it that way
+fn load_json(load: f64) -> Value {
+    if load.fract() == 0.0 && load.abs() < i64::MAX as f64 { json!(load as i64) } else { json!(load) }
+}
+
+fn has_plausible_load(server: &Value) -> bool {
+    match server["load"].as_f64() {
+        Some(load) if load > 0.0 && load <= 100.0 => true,
+        load => {
+            let load = load.map_or("missing".to_string(), |l| l.to_string());
+            let name = server["name"].as_str().unwrap_or("Unknown");
+            diagnostic(Level::Warn, "implausible_load", Some(name), &format!("skipping {} with implausible load ({}).", name, load));
+            false
+        }
+    }
+}
+
+fn calculate_distance(ulat: f64, ulon: f64, slat: f64, slon: f64) -> f64 {
+    let user_location = Location { latitude: ulat, longitude: ulon };
+    let server_location = Location { latitude: slat, longitude: slon };
+    distance(user_location, server_location, Units::Kilometers)
+}
+
+// Without a user location every distance is 0, which leaves load and name to decide the order
+fn sort_servers(mut servers: Vec<Value>, user_location: Option<(f64, f64)>, keep_api_order: bool) -> Vec<Value> {
+    for server in &mut servers {
+        server["distance"] = json!(user_location.map_or(0.0, |(ulat, ulon)| {
+            let slat = server["locations"][0]["latitude"].as_f64().unwrap();
+            let slon = server["locations"][0]["longitude"].as_f64().unwrap();
+            calculate_distance(ulat, ulon, slat, slon)
+        }));
+    }
+    if keep_api_order {
+        return servers;
+    }
+    servers.sort_by(|a, b| {
+        let a_load = server_load(a);
+        let b_load = server_load(b);
+        let a_distance = a["distance"].as_f64().unwrap();
+        let b_distance = b["distance"].as_f64().unwrap();
+        // Name breaks remaining ties so servers.json and the output order don't shuffle between runs
+        a_load.partial_cmp(&b_load).unwrap_or(Ordering::Equal)
+            .then_with(|| a_distance.partial_cmp(&b_distance).unwrap_or(Ordering::Equal))
+            .then_with(|| a["name"].as_str().cmp(&b["name"].as_str()))
+    });
+    servers
+}
+
+// NordVPN tags every server with one "regions" group (Europe, The Americas, ...), which serves as its continent
+fn server_region(server: &Value) -> &str {
+    server["groups"].as_array().into_iter().flatten()
+        .find(|group| group["type"]["identifier"] == "regions")
+        .and_then(|group| group["title"].as_str())
+        .unwrap_or("Unknown")
+}
+
+fn top_per_region(servers: &[Value], count: usize) -> BTreeMap<&str, Vec<&Value>> {
+    let mut by_region: BTreeMap<&str, Vec<&Value>> = BTreeMap::new();
+    for server in servers.iter().filter(|server| find_key(server).is_some()) {
+        by_region.entry(server_region(server)).or_default().push(server);
+    }
+    for servers in by_region.values_mut() {
+        servers.sort_by(|a, b| server_load(a).partial_cmp(&server_load(b)).unwrap_or(Ordering::Equal));
+        servers.truncate(count);
+    }
+    by_region
+}
+
+// Load is a percentage and distance is scaled by the farthest server, so both weights act on a 0-1 range
+fn fastest_server(servers: &[Value], weight_load: f64, weight_distance: f64) -> Option<&Value> {
+    let max_distance = servers.iter().filter_map(|server| server["distance"].as_f64()).fold(1.0, f64::max);
+    let score = |server: &Value| {
+        let distance = server["distance"].as_f64().unwrap_or(max_distance);
+        weight_load * server_load(server) / 100.0 + weight_distance * distance / max_distance
+    };
+    servers.iter()
+        .filter(|server| find_key(server).is_some())
+        .min_by(|a, b| score(a).partial_cmp(&score(b)).unwrap_or(Ordering::Equal))
+}
+
+// Draws one server per country in random order until every country is used, then goes round again
+fn sample_servers(servers: Vec<Value>, count: usize, rng: &mut StdRng) -> Vec<Value> {
+    let mut by_country: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for (index, server) in servers.iter().enumerate().filter(|(_, server)| find_key(server).is_some()) {
+        by_country.entry(country_city(server).0).or_default().push(index);
+    }
+    let mut countries = by_country.into_values().collect::<Vec<_>>();
+    for indices in &mut countries {
+        indices.shuffle(rng);
+    }
+    countries.shuffle(rng);
+
+    let mut picked = Vec::new();
+    while picked.len() < count && countries.iter().any(|indices| !indices.is_empty()) {
+        for indices in &mut countries {
+            if picked.len() < count {
+                picked.extend(indices.pop());
+            }
+        }
+    }
+    picked.sort_unstable();
+    // Keep the load/distance order of the sampled servers
+    servers.into_iter().enumerate().filter(|(index, _)| picked.binary_search(index).is_ok()).map(|(_, server)| server).collect()
+}
+
+// Some(false) when the hostname resolves but not to the station, None when it can't be checked
+async fn endpoint_matches(server: &Value) -> Option<bool> {
+    let hostname = server["hostname"].as_str()?;
+    let station = server["station"].as_str()?.parse::<std::net::IpAddr>().ok()?;
+    let mut addresses = tokio::net::lookup_host((hostname, 51820)).await.ok()?;
+    Some(addresses.any(|address| address.ip() == station))
+}
+
+fn export_data(servers: &[Value], private_key: Option<&str>, user_config: &UserConfig) -> Value {
+    let servers = servers.iter().filter_map(|server| {
+        let (country, city) = country_city(server);
+        let mut entry = json!({
+            "name": server["name"],
+            "hostname": server["hostname"],
+            "country": country,
+            "city": city,
+            "load": server["load"],
+            "public_key": find_key(server)?,
+            "endpoint": server_endpoint(server, user_config)?,
+        });
+        if user_config.country_routes {
+            entry["allowed_ips"] = json!(server_allowed_ips(server, user_config));
+        }
+        Some(entry)
+    }).collect::<Vec<_>>();
+    let mut data = json!({ "allowed_ips": user_config.allowed_ips, "servers": servers });
+    if let Some(key) = private_key {
+        data["private_key"] = json!(key);
+    }
+    data
+}
+
+fn nix_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"").replace("${", "\\${").replace('\n', "\\n"))
+}
+
+fn to_nix(value: &Value, indent: usize) -> String {
+    let (inner, outer) = ("  ".repeat(indent + 1), "  ".repeat(indent));
+    match value {
+        Value::Object(map) => format!("{{\n{}{}}}", map.iter().map(|(key, value)| format!("{}{} = {};\n", inner, key, to_nix(value, indent + 1))).collect::<String>(), outer),
+        Value::Array(items) => format!("[\n{}{}]", items.iter().map(|value| format!("{}{}\n", inner, to_nix(value, indent + 1))).collect::<String>(), outer),
+        Value::String(value) => nix_string(value),
+        other => other.to_string(),
+    }
+}
+
+// JSON strings are valid YAML double-quoted scalars, so serde_json does the escaping
+fn to_ansible_vars(data: &Value) -> String {
+    let mut yaml = String::from("---\n");
+    for (key, value) in data.as_object().into_iter().flatten() {
+        match value {
+            // A bare key with nothing under it would read as null, not an empty list
+            Value::Array(items) if items.is_empty() => yaml.push_str(&format!("nordvpn_{}: []\n", key)),
+            Value::Array(items) => {
+                yaml.push_str(&format!("nordvpn_{}:\n", key));
+                for item in items {
+                    for (index, (field, value)) in item.as_object().into_iter().flatten().enumerate() {
+                        yaml.push_str(&format!("{}{}: {}\n", if index == 0 { "  - " } else { "    " }, field, value));
+                    }
+                }
+            }
+            value => yaml.push_str(&format!("nordvpn_{}: {}\n", key, value)),
+        }
+    }
+    yaml
+}
+
+// reqwest follows redirects itself, so anything but a success here is a real refusal (rate limit, block page)
+async fn get_location(client: &Client) -> Result<(f64, f64, Option<String>), Box<dyn std::error::Error>> {
+    let res = send_with_retry(client.get("https://ipinfo.io/json"), "ipinfo.io").await?;
+    let status = res.status();
+    if !status.is_success() {
+        return Err(format!("ipinfo.io answered {}", status).into());
+    }
+    let body = res.text().await?;
+    let v: Value = serde_json::from_str(&body)?;
+    let (lat, lon) = v["loc"].as_str().and_then(|loc| loc.split_once(',')).ok_or("ipinfo.io returned no location")?;
+    Ok((lat.parse()?, lon.parse()?, v["country"].as_str().map(str::to_string)))
+}
+
+async fn read_location_presets(file: &Path) -> Result<BTreeMap<String, Value>, Box<dyn std::error::Error>> {
+    match fs::read_to_string(file).await {
+        Ok(contents) => Ok(serde_json::from_str(&contents)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn load_location_preset(file: &Path, name: &str) -> Result<(f64, f64, Option<String>), Box<dyn std::error::Error>> {
+    let presets = read_location_presets(file).await?;
+    let preset = presets.get(name).ok_or_else(|| format!("No location preset named {} in {}", name, file.display()))?;
+    match (preset["latitude"].as_f64(), preset["longitude"].as_f64()) {
+        (Some(lat), Some(lon)) => Ok((lat, lon, preset["country"].as_str().map(str::to_string))),
+        _ => Err(format!("Location preset {} in {} needs a numeric latitude and longitude", name, file.display()).into()),
+    }
+}
+
+async fn save_location_preset(client: &Client, file: &Path, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let (lat, lon, country) = get_location(client).await?;
+    let mut presets = read_location_presets(file).await?;
+    presets.insert(name.to_string(), json!({ "latitude": lat, "longitude": lon, "country": country }));
+    fs::write(file, serde_json::to_string_pretty(&presets)? + "\n").await?;
+    println!("Saved {}, {} as location preset {}", lat, lon, name);
+    Ok(())
+}
 
-    let body = res.text().await?;
-    let v: Value = serde_json::from_str(&body)?;
+// Returns None when the user keeps the default "All" entry
+fn choose(prompt: &str, options: &[String]) -> Option<usize> {
+    println!("{}", prompt);
+    println!("{:>4}. All", 0);
+    for (index, option) in options.iter().enumerate() {
+        println!("{:>4}. {}", index + 1, option);
+    }
+    loop {
+        print!("Enter a number [0]: ");
+        io::stdout().flush().unwrap();
+        let mut choice = String::new();
+        io::stdin().read_line(&mut choice).unwrap();
+        let choice = choice.trim();
+        if choice.is_empty() {
+            return None;
+        }
+        match choice.parse::<usize>() {
+            Ok(0) => return None,
+            Ok(n) if n <= options.len() => return Some(n - 1),
+            _ => println!("Please enter a number between 0 and {}.", options.len()),
+        }
+    }
+}
 
-    match v.get("nordlynx_private_key") {
-        Some(private_key) => Ok(private_key.as_str().unwrap().to_string()),
-        None => Err("nordlynx_private_key not found".into()),
+fn count_by<'a>(servers: &'a [Value], key: impl Fn(&'a Value) -> &'a str) -> BTreeMap<&'a str, usize> {
+    let mut counts = BTreeMap::new();
+    for server in servers {
+        *counts.entry(key(server)).or_default() += 1;
     }
+    counts
 }
 
-pub async fn get_servers(client: &Client) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
-    let res = client.get("https://api.nordvpn.com/v1/servers?limit=7000&filters[servers_technologies][identifier]=wireguard_udp").send().await?;
-    let servers: Vec<Value> = res.json().await?;
-    Ok(servers)
+fn choose_location(servers: &mut Vec<Value>) {
+    let countries = count_by(servers, |server| country_city(server).0);
+    let options = countries.iter().map(|(country, count)| format!("{} ({} servers)", country, count)).collect::<Vec<_>>();
+    let Some(index) = choose("Which country do you want configs for?", &options) else { return };
+    let country = countries.keys().nth(index).unwrap().to_string();
+    servers.retain(|server| country_city(server).0 == country);
+
+    let cities = count_by(servers, |server| country_city(server).1);
+    if cities.len() > 1 {
+        let options = cities.iter().map(|(city, count)| format!("{} ({} servers)", city, count)).collect::<Vec<_>>();
+        if let Some(index) = choose(&format!("Which city in {}?", country), &options) {
+            let city = cities.keys().nth(index).unwrap().to_string();
+            servers.retain(|server| country_city(server).1 == city);
+        }
+    }
 }
 
-pub fn find_key(server: &Value) -> Option<String> {
-    if let Some(technologies) = server.get("technologies")?.as_array() {
-        for tech in technologies {
-            if tech.get("identifier")?.as_str()? == "wireguard_udp" {
-                if let Some(metadata) = tech.get("metadata")?.as_array() {
-                    for data in metadata {
-                        if data.get("name")?.as_str()? == "public_key" {
-                            return data.get("value")?.as_str().map(|s| s.to_string());
-                        }
-                    }
+async fn monitor(client: &Client, country: &str, interval: Duration) {
+    println!("Watching the lowest-load server in {} every {}s, press Ctrl+C to stop.", country, interval.as_secs());
+    // One listener for the whole loop, so a Ctrl+C during a fetch is not lost
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        let fetched = tokio::select! {
+            _ = &mut ctrl_c => break,
+            fetched = get_servers(client, SERVER_LIMIT, None, None) => fetched,
+        };
+        match fetched {
+            Ok(servers) => {
+                let best = servers.iter()
+                    .filter(|server| matches_country(server, country) && find_key(server).is_some())
+                    .min_by(|a, b| server_load(a).partial_cmp(&server_load(b)).unwrap_or(Ordering::Equal));
+                match best {
+                    Some(server) => println!("{} ({}) load {}%", server["name"].as_str().unwrap_or("Unknown"), server["hostname"].as_str().unwrap_or(""), server_load(server)),
+                    None => eprintln!("No WireGuard servers found in {}.", country),
                 }
             }
+            Err(e) => eprintln!("Failed to fetch servers: {}", e),
+        }
+        tokio::select! {
+            _ = &mut ctrl_c => break,
+            _ = tokio::time::sleep(interval) => {}
         }
     }
-    None
 }
 
-fn format_name(name: &str) -> String {
-    let name = name.replace(" ", "_");
-    let name = name.replace("-", "");
-    name.replace("__", "_")
+async fn dry_validate(client: &Client, token: &str) -> Result<(), Box<dyn std::error::Error>> {
+    get_key(client, token, None).await?;
+    println!("Token is valid and can read WireGuard credentials.");
+    if get_servers(client, 1, None, None).await?.is_empty() {
+        return Err("The server catalog returned no WireGuard servers".into());
+    }
+    println!("Server catalog is reachable.");
+    Ok(())
 }
 
-fn generate_config(key: &str, server: &Value) -> Option<(String, String, String, String)> {
-    if let Some(public_key) = find_key(server) {
-        let country_name = format_name(server["locations"][0]["country"]["name"].as_str().unwrap());
-        let city_name = format_name(server["locations"][0]["country"].get("city").and_then(|c| c.get("name")).and_then(|n| n.as_str()).unwrap_or("Unknown"));
-        let server_name = format_name(&format!("{}_{}", server["name"].as_str().unwrap().replace("#", ""), city_name));
-        let config = format!("[Interface]
-PrivateKey = {}
-Address = 10.5.0.2/16
-DNS = 103.86.96.100
-
-[Peer]
-PublicKey = {}
-AllowedIPs = 0.0.0.0/0, ::/0
-Endpoint = {}:51820
-PersistentKeepalive = 25
-", key, public_key, server["station"].as_str().unwrap());
-        Some((country_name, city_name, server_name, config))
-    } else {
-        println!("No WireGuard public key found for {} in {}. Skipping.", server["name"].as_str().unwrap(), server.get("city").and_then(|c| c.get("name")).and_then(|n| n.as_str()).unwrap_or("Unknown"));
-        None
+fn print_country_counts(servers: &[Value]) {
+    let mut loads: BTreeMap<&str, Vec<f64>> = BTreeMap::new();
+    for server in servers {
+        loads.entry(country_city(server).0).or_default().push(server_load(server));
+    }
+    let mut countries = loads.into_iter().collect::<Vec<_>>();
+    countries.sort_by_key(|(_, loads)| std::cmp::Reverse(loads.len()));
+    println!("{:<32} {:>7} {:>5} {:>7}", "Country", "Servers", "Min", "Median");
+    for (country, mut loads) in countries {
+        loads.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        let mid = loads.len() / 2;
+        let median = if loads.len() % 2 == 0 { (loads[mid - 1] + loads[mid]) / 2.0 } else { loads[mid] };
+        println!("{:<32} {:>7} {:>5} {:>7}", country, loads.len(), loads[0], median);
     }
 }
 
-async fn save_config(key: Arc<String>, server: &Value, path: Option<&str>) -> Result<Option<String>, Box<dyn std::error::Error>> {
-    if server.get("locations").is_some() {
-        if let Some((country_folder, city_folder, server_name, config)) = generate_config(&key, server) {
-            let path = match path {
-                Some(p) => p.to_string(),
-                None => {
-                    let country_path = Path::new("configs").join(&country_folder);
-                    fs::create_dir_all(&country_path).await?;
-                    let city_path = country_path.join(&city_folder);
-                    fs::create_dir_all(&city_path).await?;
-                    city_path.join(format!("{}.conf", server_name)).to_str().unwrap().to_string()
-                }
-            };
-            fs::write(&path, config).await?;
-            println!("WireGuard configuration for {} saved to {}", server_name, path);
-            Ok(Some(path))
+async fn ping_unfragmented(host: &str, payload: u32) -> bool {
+    tokio::process::Command::new("ping")
+        .args(["-c", "1", "-W", "1", "-M", "do", "-s", &payload.to_string(), host])
+        .output()
+        .await
+        .is_ok_and(|output| output.status.success())
+}
+
+// Binary search between IPv6's minimum MTU and Ethernet's 1500 with Don't Fragment set, which needs Linux's ping
+async fn probe_path_mtu(host: &str) -> Option<u32> {
+    let (mut low, mut high) = (1280, 1500);
+    if !ping_unfragmented(host, low - PING_OVERHEAD).await {
+        return None;
+    }
+    while low < high {
+        let mid = (low + high).div_ceil(2);
+        if ping_unfragmented(host, mid - PING_OVERHEAD).await {
+            low = mid;
         } else {
-            Ok(None)
+            high = mid - 1;
         }
+    }
+    Some(low)
+}
+
+// Adds the MTU to a wg-quick [Interface] block, or as one more sorted line of an env config
+fn with_mtu(config: &str, mtu: u32) -> String {
+    if config.contains("[Interface]") {
+        config.replacen("Address = ", &format!("MTU = {}\nAddress = ", mtu), 1)
     } else {
-        Ok(None)
+        let mut lines = config.lines().chain([format!("MTU='{}'", mtu).as_str()]).map(str::to_string).collect::<Vec<_>>();
+        lines.sort();
+        lines.join("\n") + "\n"
     }
 }
 
-fn calculate_distance(ulat: f64, ulon: f64, slat: f64, slon: f64) -> f64 {
-    let user_location = Location { latitude: ulat, longitude: ulon };
-    let server_location = Location { latitude: slat, longitude: slon };
-    distance(user_location, server_location, Units::Kilometers)
+// Configs differ only in a few short fields, so one sample times the file count is close; AllowedIPs from --route-country dominates anyway.
+// servers.json adds one line per server, plus its details when they are kept.
+fn estimate_output_size(key: &str, servers: &[Value], user_config: &UserConfig, files: usize, full_server_info: bool) -> Option<u64> {
+    let sample = servers.iter()
+        .filter(|server| find_key(server).is_some() && server_endpoint(server, user_config).is_some())
+        .find_map(|server| generate_config(key, server, user_config))?.3;
+    let mut per_file = sample.len() as u64;
+    if user_config.base64 {
+        per_file += per_file.div_ceil(3) * 4;
+    }
+    let server_info = servers.iter().map(|server| {
+        let details = if full_server_info { serde_json::to_string_pretty(&server_details(server)).map_or(0, |details| details.len()) } else { 0 };
+        (server["name"].as_str().map_or(0, str::len) + 20 + details) as u64
+    }).sum::<u64>();
+    Some(per_file * files as u64 + server_info)
 }
 
-fn sort_servers(mut servers: Vec<Value>, ulat: f64, ulon: f64) -> Vec<Value> {
-    for server in &mut servers {
-        let slat = server["locations"][0]["latitude"].as_f64().unwrap();
-        let slon = server["locations"][0]["longitude"].as_f64().unwrap();
-        server["distance"] = json!(calculate_distance(ulat, ulon, slat, slon));
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..1_000 => format!("{} B", bytes),
+        1_000..1_000_000 => format!("{:.1} kB", bytes as f64 / 1e3),
+        1_000_000..1_000_000_000 => format!("{:.1} MB", bytes as f64 / 1e6),
+        _ => format!("{:.1} GB", bytes as f64 / 1e9),
     }
-    servers.sort_by(|a, b| {
-        let a_load = a["load"].as_f64().unwrap();
-        let b_load = b["load"].as_f64().unwrap();
-        let a_distance = a["distance"].as_f64().unwrap();
-        let b_distance = b["distance"].as_f64().unwrap();
-        a_load.partial_cmp(&b_load).unwrap_or(Ordering::Equal).then_with(|| a_distance.partial_cmp(&b_distance).unwrap_or(Ordering::Equal))
-    });
-    servers
 }
 
-async fn get_location() -> Result<(f64, f64), Box<dyn std::error::Error>> {
-    let res = get("https://ipinfo.io/json").await?;
-    let body = res.text().await?;
-    let v: Value = serde_json::from_str(&body)?;
-    let loc = v["loc"].as_str().unwrap().split(',').collect::<Vec<&str>>();
-    Ok((loc[0].parse()?, loc[1].parse()?))
+// Whole seconds read best for long runs, but a sub-second run would just say 0s
+fn format_elapsed(elapsed: Duration, machine: bool) -> String {
+    if machine {
+        format!("{:.2}s", elapsed.as_secs_f64())
+    } else if elapsed.as_secs() > 0 {
+        humantime::format_duration(Duration::from_secs(elapsed.as_secs())).to_string()
+    } else {
+        humantime::format_duration(Duration::from_millis(elapsed.as_millis() as u64)).to_string()
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut token = String::new();
-    print!("Please enter your token: ");
-    io::stdout().flush().unwrap(); // Flush stdout to display the prompt before waiting for input
-    io::stdin().read_line(&mut token).unwrap();
+// Quotes a path for the shell run_post_command uses, so spaces or $ in it stay part of the path
+fn shell_quote(path: &Path) -> String {
+    let path = path.display().to_string();
+    if cfg!(windows) {
+        // cmd has no escape for " inside quotes, but Windows paths can't contain it
+        format!("\"{}\"", path)
+    } else {
+        format!("'{}'", path.replace('\'', "'\\''"))
+    }
+}
+
+async fn run_post_command(command: &str, dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let command = command.replace("{dir}", &shell_quote(dir));
+    let (shell, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+    println!("Running post-run command: {}", command);
+    let status = tokio::process::Command::new(shell).arg(flag).arg(&command).status().await?;
+    if status.success() {
+        println!("Post-run command finished successfully");
+    } else {
+        diagnostic(Level::Error, "post_run_failed", None, &format!("Post-run command exited with {}", status));
+    }
+    Ok(())
+}
+
+// .conf and .env files under configs/<country>/<city>/ whose server is not in the catalog (paths without extension)
+async fn stale_configs(country_dir: &Path, catalog: &HashSet<PathBuf>) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut stale = Vec::new();
+    let Ok(mut cities) = fs::read_dir(country_dir).await else {
+        return Ok(stale);
+    };
+    while let Some(city) = cities.next_entry().await? {
+        if !city.file_type().await?.is_dir() {
+            continue;
+        }
+        let mut files = fs::read_dir(city.path()).await?;
+        while let Some(file) = files.next_entry().await? {
+            let path = file.path();
+            if path.extension().is_some_and(|ext| ext == "conf" || ext == "env") && !catalog.contains(&path.with_extension("")) {
+                stale.push(path);
+            }
+        }
+    }
+    Ok(stale)
+}
+
+// The key, virtual location, load and city size filters shared by generation and --count-per-country
+fn filter_servers(args: &Args, servers: &mut Vec<Value>) {
+    let fetched = servers.len();
+    servers.retain(|server| find_key(server).is_none_or(|key| is_valid_key(&key)));
+    if servers.len() < fetched {
+        diagnostic(Level::Warn, "malformed_keys", None, &format!("skipped {} servers with a malformed WireGuard public key.", fetched - servers.len()));
+    }
+
+    if args.no_virtual || args.only_virtual {
+        if servers.iter().any(|server| is_virtual(server).is_some()) {
+            servers.retain(|server| is_virtual(server).unwrap_or(false) == args.only_virtual);
+        } else {
+            diagnostic(Level::Warn, "virtual_unmarked", None, "the API response does not mark virtual locations, so no servers were filtered.");
+        }
+    }
+
+    if args.strict_load {
+        servers.retain(has_plausible_load);
+    }
+
+    if !args.country.is_empty() {
+        servers.retain(|server| args.country.iter().any(|country| matches_country(server, country)));
+    }
+    if !args.city.is_empty() {
+        servers.retain(|server| args.city.iter().any(|city| same_place(country_city(server).1, city)));
+    }
+    if let Some(max_load) = args.max_load {
+        servers.retain(|server| server_load(server) <= max_load);
+    }
+
+    if args.min_servers_per_city > 1 {
+        let mut city_sizes: HashMap<(String, String), usize> = HashMap::new();
+        for server in servers.iter() {
+            let (country, city) = country_city(server);
+            *city_sizes.entry((country.to_string(), city.to_string())).or_default() += 1;
+        }
+        servers.retain(|server| {
+            let (country, city) = country_city(server);
+            city_sizes[&(country.to_string(), city.to_string())] >= args.min_servers_per_city
+        });
+        let dropped = city_sizes.values().filter(|&&size| size < args.min_servers_per_city).count();
+        println!("Dropped {} cities with fewer than {} servers.", dropped, args.min_servers_per_city);
+    }
+
+    if let Some(count) = args.top_n {
+        let mut by_city: HashMap<(&str, &str), Vec<(f64, &str)>> = HashMap::new();
+        for server in servers.iter() {
+            by_city.entry(country_city(server)).or_default().push((server_load(server), server["name"].as_str().unwrap_or("")));
+        }
+        let mut kept = HashSet::new();
+        for mut city in by_city.into_values() {
+            city.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal).then_with(|| a.1.cmp(b.1)));
+            kept.extend(city.into_iter().take(count as usize).map(|(_, name)| name.to_string()));
+        }
+        servers.retain(|server| server["name"].as_str().is_some_and(|name| kept.contains(name)));
+    }
+}
+
+// The server list from --from-server-info or the API, with the --group filter applied
+async fn load_servers(args: &Args, client: &Client) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+    if let Some(path) = &args.from_server_info {
+        let servers = read_server_info(path).await?;
+        if servers.is_empty() {
+            return Err(format!("{} lists no servers", path.display()).into());
+        }
+        return Ok(servers);
+    }
+    let dump = args.dump_api.as_deref();
+    let servers = match (get_servers(client, SERVER_LIMIT, args.group.as_deref(), dump).await, &args.group) {
+        (Ok(servers), _) => servers,
+        (Err(e), Some(group)) => {
+            diagnostic(Level::Warn, "group_filter_rejected", None, &format!("the API rejected the group filter ({}), filtering locally instead.", e));
+            let servers = get_servers(client, SERVER_LIMIT, None, dump).await?;
+            servers.into_iter().filter(|server| in_group(server, group)).collect()
+        }
+        (Err(e), None) => return Err(e),
+    };
+    if servers.is_empty() {
+        if let Some(group) = &args.group {
+            return Err(format!("No WireGuard servers are in group {}. Run the groups subcommand to list valid identifiers.", group).into());
+        }
+        return Err("NordVPN returned no WireGuard servers. The API may be under maintenance or the wireguard_udp technology filter may have changed; please try again later.".into());
+    }
+    Ok(servers)
+}
+
+async fn run(args: &Args, client: &Client, token: &str) -> Result<RunReport, Box<dyn std::error::Error>> {
+    let start = Instant::now();
+    let mut api_timings = Vec::new();
+    let timer = Instant::now();
+    let mut servers = load_servers(args, client).await?;
+    if args.from_server_info.is_none() {
+        api_timings.push(("get_servers", timer.elapsed()));
+    }
+    // Taken before any filter, so --update-country only removes configs of servers NordVPN no longer lists
+    let catalog = if args.update_country.is_some() {
+        servers.iter()
+            .filter(|server| server.get("locations").is_some())
+            .map(|server| {
+                let (country, city, name) = location_names(server);
+                Path::new("configs").join(country).join(city).join(name)
+            })
+            .collect::<HashSet<_>>()
+    } else {
+        HashSet::new()
+    };
+    let credentials_dump = args.dump_api.as_ref().map(|path| path.with_extension("credentials.json"));
+    let timer = Instant::now();
+    let private_key = Arc::new(get_key(client, token, credentials_dump.as_deref()).await?);
+    api_timings.push(("get_key", timer.elapsed()));
+
+    if let Some(endpoint) = &args.endpoint_override {
+        diagnostic(Level::Warn, "endpoint_override", None, &format!("every config will connect to {}. This only works if it forwards to the matching NordVPN server.", endpoint));
+    }
+    let allowed_ips = match &args.route_country {
+        Some(code) => {
+            let ranges = get_country_ranges(client, code).await?;
+            println!("Routing {} IP ranges of {} through the tunnel", ranges.len(), code.to_uppercase());
+            ranges.join(", ")
+        }
+        None => "0.0.0.0/0, ::/0".to_string(),
+    };
+    let user_config = Arc::new(UserConfig {
+        endpoint_override: args.endpoint_override.clone(),
+        endpoint_family: args.endpoint_family,
+        keepalive: args.keepalive,
+        base64: args.base64,
+        fwmark: args.fwmark,
+        dns: args.dns.as_ref().map(|dns| dns.0.clone()).filter(|dns| !dns.is_empty()).unwrap_or_else(|| vec![DEFAULT_DNS]),
+        dns_search: args.dns_search.clone(),
+        use_ip: args.use_ip,
+        save_config: args.save_config,
+        allowed_ips,
+        country_routes: args.route_country.is_some(),
+        name_comment: args.rank_filenames,
+        endpoint_both: args.endpoint_both,
+        only_changed: args.update_country.is_some(),
+        format: args.format,
+        amnezia: args.amnezia,
+    });
+    if !user_config.is_valid() {
+        return Err("The settings can't produce a working config: check --keepalive, --dns, --route-country and that --awg-jmin is not above --awg-jmax.".into());
+    }
+
+    filter_servers(args, &mut servers);
+
+    if let Some(country) = &args.update_country {
+        servers.retain(|server| matches_country(server, country));
+    }
 
-    let client = Client::new();
-    let mut servers = get_servers(&client).await?;
-    let private_key = Arc::new(get_key(&client, token.trim()).await?);
+    let location_given = !args.country.is_empty() || !args.city.is_empty() || args.update_country.is_some();
+    // Single-output modes and offline rebuilds already say what they want, so only the full tree asks
+    let full_tree_run = !args.fastest && args.top_per_continent.is_none() && args.from_server_info.is_none()
+        && !matches!(args.format, OutputFormat::Nix | OutputFormat::AnsibleVars);
+    if !args.all && !args.non_interactive && !location_given && full_tree_run && io::stdin().is_terminal() && !servers.is_empty() {
+        choose_location(&mut servers);
+    }
+
+    if servers.is_empty() {
+        diagnostic(Level::Warn, "no_servers", None, "no servers match the given filters, nothing was generated.");
+        return Ok(RunReport {
+            configs_written: 0,
+            errors: Vec::new(),
+            best_servers: Vec::new(),
+            elapsed: start.elapsed(),
+            api_timings,
+            output_dir: std::env::current_dir()?,
+            wrote_output: false,
+        });
+    }
+
+    if args.validate_endpoints {
+        let results = stream::iter(&servers).map(endpoint_matches).buffered(DNS_CONCURRENCY).collect::<Vec<_>>().await;
+        for (server, result) in servers.iter().zip(&results) {
+            let hostname = server["hostname"].as_str().unwrap_or("Unknown");
+            match result {
+                Some(false) => diagnostic(Level::Warn, "endpoint_mismatch", server["name"].as_str(), &format!("{} does not resolve to its station {}, the catalog entry may be stale.", hostname, server["station"].as_str().unwrap_or(""))),
+                None => diagnostic(Level::Warn, "unresolved_hostname", server["name"].as_str(), &format!("could not resolve {}.", hostname)),
+                Some(true) => {}
+            }
+        }
+        if args.skip_mismatched {
+            let mut results = results.into_iter();
+            servers.retain(|_| results.next() != Some(Some(false)));
+        }
+    }
+
+    let location = if args.no_distance {
+        None
+    } else if let Some(name) = &args.location_preset {
+        Some(load_location_preset(&args.presets_file, name).await?)
+    } else {
+        let timer = Instant::now();
+        let location = get_location(client).await;
+        api_timings.push(("get_location", timer.elapsed()));
+        location.map_err(|e| {
+            diagnostic(Level::Warn, "location_unavailable", None, &format!("could not look up your location ({}), sorting by load only.", e));
+        }).ok()
+    };
+    let user_location = location.as_ref().map(|(ulat, ulon, _)| (*ulat, *ulon));
+    let user_country = location.and_then(|(_, _, country)| country);
+    servers = sort_servers(servers, user_location, args.no_sort);
+
+    if let Some(count) = args.sample {
+        let seed = args.seed.unwrap_or_else(|| rand::thread_rng().gen());
+        println!("Sampling {} servers with seed {}", count, seed);
+        servers = sample_servers(servers, count, &mut StdRng::seed_from_u64(seed));
+    }
+
+    if args.fastest {
+        let server = fastest_server(&servers, args.weight_load, args.weight_distance).ok_or("No server with a WireGuard key is available")?;
+        let path = args.out_file.as_ref().map_or("fastest.conf".to_string(), |path| path.display().to_string());
+        save_config(Arc::clone(&private_key), Arc::clone(&user_config), server, Some(&path)).await?;
+        return Ok(RunReport {
+            configs_written: 1,
+            errors: Vec::new(),
+            best_servers: vec![server["name"].as_str().unwrap_or("Unknown").to_string()],
+            elapsed: start.elapsed(),
+            api_timings,
+            output_dir: std::env::current_dir()?,
+            wrote_output: true,
+        });
+    }
+
+    if let Some(count) = args.top_per_continent {
+        let mut configs_written = 0;
+        let mut best_servers = Vec::new();
+        for (region, servers) in top_per_region(&servers, count) {
+            for server in servers {
+                let path = format!("top_per_continent/{}/{}.conf", format_name(region), location_names(server).2);
+                if save_config(Arc::clone(&private_key), Arc::clone(&user_config), server, Some(&path)).await?.is_some() {
+                    configs_written += 1;
+                    best_servers.push(server["name"].as_str().unwrap_or("Unknown").to_string());
+                }
+            }
+        }
+        return Ok(RunReport {
+            configs_written,
+            errors: Vec::new(),
+            best_servers,
+            elapsed: start.elapsed(),
+            api_timings,
+            output_dir: std::env::current_dir()?,
+            wrote_output: configs_written > 0,
+        });
+    }
+
+    let preview_key = if args.show_key { private_key.as_str() } else { "<redacted>" };
+    for server in servers.iter().filter(|server| find_key(server).is_some()).take(args.preview) {
+        if let Some((_, _, server_name, config)) = generate_config(preview_key, server, &user_config) {
+            eprintln!("--- {} ---\n{}", server_name, config);
+        }
+    }
+
+    if matches!(args.format, OutputFormat::Nix | OutputFormat::AnsibleVars) {
+        let key = if args.redact_keys { None } else { Some(private_key.as_str()) };
+        let data = export_data(&servers, key, &user_config);
+        let (path, contents) = match args.format {
+            OutputFormat::Nix => ("servers.nix", to_nix(&data, 0) + "\n"),
+            _ => ("servers.yml", to_ansible_vars(&data)),
+        };
+        fs::write(path, contents).await?;
+        let exported = data["servers"].as_array().map_or(0, Vec::len);
+        println!("Exported {} servers to {}", exported, path);
+        return Ok(RunReport {
+            configs_written: 0,
+            errors: Vec::new(),
+            best_servers: Vec::new(),
+            elapsed: start.elapsed(),
+            api_timings,
+            output_dir: std::env::current_dir()?,
+            wrote_output: exported > 0,
+        });
+    }
+
+    for country in &args.full_country {
+        if !servers.iter().any(|server| matches_country(server, country)) {
+            diagnostic(Level::Warn, "unknown_country", None, &format!("--full-country {} does not match any server.", country));
+        }
+    }
+    let full_tree = servers.iter().filter(|server| args.full_country.is_empty() || args.full_country.iter().any(|country| matches_country(server, country))).enumerate();
+    let rank_width = servers.len().to_string().len().max(2);
+    // The user's own country is written first so those files exist even if a large run is interrupted
+    let (home, abroad): (Vec<_>, Vec<_>) = full_tree.partition(|(_, server)| user_country.as_deref().is_some_and(|country| matches_country(server, country)));
 
-    let (ulat, ulon) = get_location().await?;
-    servers = sort_servers(servers, ulat, ulon);
+    let cities = servers.iter().map(country_city).collect::<HashSet<_>>().len();
+    let countries = servers.iter().map(|server| country_city(server).0).collect::<HashSet<_>>().len();
+    // A failover file holds up to N peers, so it weighs about as much as N configs
+    let country_files = countries * (usize::from(args.best_per_country) + args.failover.unwrap_or(0));
+    let full_server_info = args.full_server_info || args.from_server_info.is_some();
+    if let Some(bytes) = estimate_output_size(&private_key, &servers, &user_config, home.len() + abroad.len() + cities + country_files, full_server_info) {
+        println!("Writing {} configs and {} best configs, about {}", home.len() + abroad.len(), cities, format_size(bytes));
+    }
 
-    let tasks: Vec<_> = servers.iter().cloned().map(|server| {
+    let io_slots = Arc::new(Semaphore::new(args.io_throttle.map_or(Semaphore::MAX_PERMITS, |n| n as usize)));
+    let tasks: Vec<_> = home.into_iter().chain(abroad).map(|(rank, server)| {
+		let server = server.clone();
 		let private_key = Arc::clone(&private_key);
+		let user_config = Arc::clone(&user_config);
+		let io_slots = Arc::clone(&io_slots);
+		let path = match args.group_by {
+			_ if args.rank_filenames => Some(format!("configs/{:0width$}.conf", rank + 1, width = rank_width)),
+			GroupBy::Location => None,
+			GroupBy::Load => server.get("locations").map(|_| {
+				let bucket = load_bucket(server_load(&server), args.medium_load, args.high_load);
+				format!("configs/{}/{}.conf", bucket, location_names(&server).2)
+			}),
+			GroupBy::Label => server.get("locations").map(|_| {
+				let label = server_labels(&server, &args.label).first().copied().unwrap_or("unlabeled");
+				format!("configs/{}/{}.conf", format_name(label), location_names(&server).2)
+			}),
+		};
 		task::spawn(async move {
-			match save_config(private_key, &server, None).await {
-				Ok(_) => (),
-				Err(e) => eprintln!("Error saving config for server {}: {}", server["name"].as_str().unwrap_or("Unknown"), e),
+			let _slot = io_slots.acquire().await.unwrap();
+			match save_config(private_key, user_config, &server, path.as_deref()).await {
+				Ok(saved) => Ok(saved),
+				Err(e) => {
+					let name = server["name"].as_str().unwrap_or("Unknown").to_string();
+					diagnostic(Level::Error, "save_failed", Some(&name), &format!("Error saving config for server {}: {}", name, e));
+					Err((name, e.to_string()))
+				}
 			}
 		})
 	}).collect();
 
+    let mut saved_paths = HashSet::new();
+    let mut errors = Vec::new();
     for t in tasks {
-        t.await?;
+        match t.await? {
+            Ok(saved) => saved_paths.extend(saved.filter(|(_, changed)| *changed).map(|(path, _)| PathBuf::from(path))),
+            Err(error) => errors.push(error),
+        }
+    }
+    let configs_written = saved_paths.len();
+    let mut removed = 0;
+
+    // Servers that left the catalog would otherwise keep their stale configs in the updated country.
+    // A failed run keeps everything, so a transient write error never costs a working config.
+    if let (Some(_), Some(server), true) = (&args.update_country, servers.first(), errors.is_empty()) {
+        let country_dir = Path::new("configs").join(location_names(server).0);
+        for path in stale_configs(&country_dir, &catalog).await? {
+            fs::remove_file(&path).await?;
+            let encoded = PathBuf::from(format!("{}.b64", path.display()));
+            if encoded.exists() {
+                fs::remove_file(encoded).await?;
+            }
+            println!("Removed {}", path.display());
+            removed += 1;
+        }
     }
 
-    let mut servers_by_location: HashMap<String, HashMap<String, Vec<Vec<String>>>> = HashMap::new();
+    let mut servers_by_location: HashMap<String, HashMap<String, Vec<(String, f64)>>> = HashMap::new();
 	for server in &servers {
-		let country = server["locations"][0]["country"]["name"].as_str().unwrap().to_string();
-		let city = server["locations"][0]["country"]["city"]["name"].as_str().unwrap_or("Unknown").to_string();
-		let server_info = vec![server["name"].as_str().unwrap().to_string(), server["load"].as_f64().unwrap().to_string()];
-		servers_by_location.entry(country).or_insert_with(HashMap::new).entry(city).or_insert_with(Vec::new).push(server_info);
+		let (country, city) = country_city(server);
+		let (country, city) = (country.to_string(), city.to_string());
+		let server_info = (server["name"].as_str().unwrap().to_string(), server_load(server));
+		servers_by_location.entry(country).or_default().entry(city).or_default().push(server_info);
 	}
 
-    for (_, cities) in &mut servers_by_location {
-        for (_, servers) in cities {
-            servers.sort_by(|a, b| a[1].parse::<f64>().unwrap().partial_cmp(&b[1].parse::<f64>().unwrap()).unwrap());
+    for cities in servers_by_location.values_mut() {
+        for servers in cities.values_mut() {
+            servers.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
         }
     }
 
     fs::create_dir_all("best_configs").await?;
 
     let original_servers = servers.clone(); // Clone the servers vector
+    let mut best_servers = Vec::new();
 
+    let mut best_saved = Vec::new();
+    let mut use_api_score = args.use_api_score;
     for (country, cities) in &servers_by_location {
         let safe_country_name = country.replace(" ", "_");
+        let country_id = use_api_score.then(|| original_servers.iter()
+            .find(|server| country_city(server).0 == country)
+            .and_then(|server| server["locations"][0]["country"]["id"].as_u64())).flatten();
+        let recommended = match country_id {
+            Some(id) => get_recommendations(client, id).await.unwrap_or_else(|e| {
+                let code = match e.downcast_ref::<reqwest::Error>().and_then(reqwest::Error::status) {
+                    Some(StatusCode::TOO_MANY_REQUESTS) => "rate_limited",
+                    _ => "recommendations_unavailable",
+                };
+                diagnostic(Level::Warn, code, None, &format!("NordVPN recommendations are unavailable ({}), picking best configs by load.", e));
+                use_api_score = false;
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+        let mut country_best: Option<(&(String, f64), &Value)> = None;
         for (city, servers) in cities {
-            let best_server = &servers[0];
+            // Cities without a recommended server keep the lowest-load pick
+            let best_server = recommended.iter()
+                .find_map(|name| servers.iter().find(|(server, _)| server == name))
+                .unwrap_or(&servers[0]);
+            if args.explain && best_server.0 != servers[0].0 {
+                println!("{}, {}: chose {} load={} as recommended by NordVPN over lowest-load {} load={}", city, country, best_server.0, best_server.1, servers[0].0, servers[0].1);
+            } else if args.explain {
+                match servers.get(1) {
+                    Some(runner_up) => println!("{}, {}: chose {} load={} over {} load={}", city, country, best_server.0, best_server.1, runner_up.0, runner_up.1),
+                    None => println!("{}, {}: chose {} load={} as the only server", city, country, best_server.0, best_server.1),
+                }
+            }
             // Find the server Value that corresponds to the best server
-            let best_server_value = original_servers.iter().find(|server| server["name"].as_str().unwrap() == best_server[0]).unwrap();
+            let best_server_value = original_servers.iter().find(|server| server["name"].as_str().unwrap() == best_server.0).unwrap();
             let safe_city_name = city.replace(" ", "_");
             // Save the config for the best server
-            save_config(Arc::clone(&private_key), best_server_value, Some(&format!("best_configs/{}_{}.conf", safe_country_name, safe_city_name))).await?;
+            let best_path = format!("best_configs/{}_{}.conf", safe_country_name, safe_city_name);
+            if let Some((saved_path, changed)) = save_config(Arc::clone(&private_key), Arc::clone(&user_config), best_server_value, Some(&best_path)).await? {
+                if changed {
+                    best_servers.push(best_server.0.clone());
+                }
+                best_saved.push((best_server.1, saved_path, best_server_value));
+                if let Some(iface) = &args.emit_service {
+                    let service = generate_service(iface, &best_path, &format!("{}, {}", city, country));
+                    fs::write(format!("best_configs/{}_{}.service", safe_country_name, safe_city_name), service).await?;
+                }
+                if country_best.is_none_or(|(current, _)| (best_server.1, &best_server.0) < (current.1, &current.0)) {
+                    country_best = Some((best_server, best_server_value));
+                }
+            }
+        }
+        // Picked from the city bests, so no server is generated twice
+        if let Some((_, server)) = country_best.filter(|_| args.best_per_country) {
+            let path = format!("best_per_country/{}.conf", safe_country_name);
+            save_config(Arc::clone(&private_key), Arc::clone(&user_config), server, Some(&path)).await?;
+        }
+    }
+
+    if let Some(count) = args.probe_mtu {
+        best_saved.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+        for (_, path, server) in best_saved.iter().take(count) {
+            let Some(station) = server["station"].as_str() else { continue };
+            match probe_path_mtu(station).await {
+                Some(path_mtu) => {
+                    let mtu = path_mtu - WIREGUARD_OVERHEAD;
+                    let config = with_mtu(&fs::read_to_string(path).await?, mtu);
+                    // The .b64 copy was encoded before the probe, so it needs the MTU too
+                    if user_config.base64 {
+                        fs::write(format!("{}.b64", path), STANDARD.encode(&config)).await?;
+                    }
+                    fs::write(path, config).await?;
+                    println!("Path MTU to {} is {}, set MTU = {} in {}", station, path_mtu, mtu, path);
+                }
+                None => diagnostic(Level::Warn, "mtu_probe_failed", server["name"].as_str(), &format!("could not measure the path MTU to {}, {} keeps the default MTU.", station, path)),
+            }
+        }
+    }
+
+    if let Some(count) = args.failover {
+        let mut servers_by_country: BTreeMap<&str, Vec<&Value>> = BTreeMap::new();
+        for server in original_servers.iter().filter(|server| find_key(server).is_some()) {
+            let country = servers_by_country.entry(country_city(server).0).or_default();
+            if country.len() < count {
+                country.push(server);
+            }
+        }
+        fs::create_dir_all("failover_configs").await?;
+        for (country, servers) in &servers_by_country {
+            let config = generate_failover_config(&private_key, country, servers, &user_config);
+            fs::write(format!("failover_configs/{}.conf", format_name(country)), config).await?;
+        }
+    }
+
+    // A rebuild keeps the details so servers.json can be reused again
+    let full = args.full_server_info || args.from_server_info.is_some();
+    let by_name = original_servers.iter().map(|server| (server["name"].as_str().unwrap(), server)).collect::<HashMap<_, _>>();
+    // Plain [name, load] unless details or labels add a third element
+    let entry = |name: &String, load: &f64| {
+        let server = by_name[name.as_str()];
+        let mut extra = if full { server_details(server).as_object().cloned().unwrap_or_default() } else { serde_json::Map::new() };
+        let labels = server_labels(server, &args.label);
+        if !labels.is_empty() {
+            extra.insert("labels".to_string(), json!(labels));
+        }
+        let load = load_json(*load);
+        if extra.is_empty() { json!([name, load]) } else { json!([name, load, extra]) }
+    };
+    let mut server_info = serde_json::to_value(servers_by_location.iter()
+        .map(|(country, cities)| (country, cities.iter()
+            .map(|(city, servers)| (city, servers.iter().map(|(name, load)| entry(name, load)).collect::<Vec<_>>()))
+            .collect::<BTreeMap<_, _>>()))
+        .collect::<BTreeMap<_, _>>())?;
+    if args.update_country.is_some() {
+        // Other countries keep the entries from the previous run
+        if let Ok(Value::Object(mut previous)) = fs::read_to_string("servers.json").await.map(|contents| serde_json::from_str(&contents).unwrap_or(Value::Null)) {
+            if let Value::Object(updated) = server_info {
+                previous.extend(updated);
+            }
+            server_info = Value::Object(previous);
         }
     }
+    let server_info = if args.compact_json {
+        serde_json::to_string(&server_info)?
+    } else {
+        serde_json::to_string_pretty(&server_info)?
+    };
+    fs::write("servers.json", server_info + "\n").await?;
 
-    let servers_by_location = servers_by_location.into_iter().collect::<BTreeMap<_, _>>();
+    // Keep a record of failed servers so they can be retried after the terminal scrolls away
+    if errors.is_empty() {
+        if Path::new("errors.log").exists() {
+            fs::remove_file("errors.log").await?;
+        }
+    } else {
+        let log = errors.iter().map(|(name, e)| format!("{}: {}\n", name, e)).collect::<String>();
+        fs::write("errors.log", log).await?;
+    }
 
-    // Make file mutable
-    let mut file = File::create("servers.json").await?;
+    let wrote_output = configs_written > 0 || !best_servers.is_empty() || removed > 0;
+    Ok(RunReport {
+        configs_written,
+        errors,
+        best_servers,
+        elapsed: start.elapsed(),
+        api_timings,
+        output_dir: std::env::current_dir()?,
+        wrote_output,
+    })
+}
 
-    let last_country_index = servers_by_location.len() - 1;
-    file.write_all(b"{\n").await?;
-    for (index, (country, cities)) in servers_by_location.iter().enumerate() {
-        file.write_all(format!("  \"{}\": {{\n", country).as_bytes()).await?;
-        let last_city_index = cities.len() - 1;
-        for (city_index, (city, servers)) in cities.iter().enumerate() {
-            file.write_all(format!("    \"{}\": [\n", city).as_bytes()).await?;
-            let last_server_index = servers.len() - 1;
-            for (server_index, server) in servers.iter().enumerate() {
-                file.write_all(format!("      [\"{}\", {}]", server[0], server[1]).as_bytes()).await?;
-                if server_index < last_server_index {
-                    file.write_all(b",\n").await?;
-                } else {
-                    file.write_all(b"\n").await?;
-                }
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    // Each --awg-* parser only sees its own value, so the pair is checked here, before the lock and token prompt
+    if args.amnezia.jmin > args.amnezia.jmax {
+        Args::command()
+            .error(clap::error::ErrorKind::ArgumentConflict, format!("--awg-jmin ({}) can't be above --awg-jmax ({})", args.amnezia.jmin, args.amnezia.jmax))
+            .exit();
+    }
+    JSON_DIAGNOSTICS.store(args.json_diagnostics, AtomicOrdering::Relaxed);
+    // Returning the error from main would print its Debug form, quotes included, and skip --json-diagnostics
+    if let Err(e) = execute(args).await {
+        diagnostic(Level::Error, "fatal", None, &e.to_string());
+        std::process::exit(1);
+    }
+}
+
+async fn execute(mut args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    args.presets_file = std::env::current_dir()?.join(LOCATION_PRESETS);
+    let client = Client::builder().timeout(API_TIMEOUT).build()?;
+
+    match &args.command {
+        Some(Command::Groups) => {
+            for group in get_groups(&client).await? {
+                println!("{:<32} {}", group["identifier"].as_str().unwrap_or(""), group["title"].as_str().unwrap_or(""));
+            }
+            return Ok(());
+        }
+        Some(Command::ListCities { country, sort }) => {
+            let servers = get_servers(&client, SERVER_LIMIT, None, None).await?;
+            let servers = servers.into_iter().filter(|server| matches_country(server, country)).collect::<Vec<_>>();
+            let mut cities = count_by(&servers, |server| country_city(server).1).into_iter().collect::<Vec<_>>();
+            if cities.is_empty() {
+                return Err(format!("No WireGuard servers found in {}", country).into());
+            }
+            if *sort == CitySort::Count {
+                cities.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
             }
-            file.write_all(b"    ]").await?;
-            if city_index < last_city_index {
-                file.write_all(b",\n").await?;
-            } else {
-                file.write_all(b"\n").await?;
+            for (city, count) in cities {
+                println!("{:<32} {}", city, count);
             }
+            return Ok(());
         }
-        file.write_all(b"  }").await?;
-        if index < last_country_index {
-            file.write_all(b",\n").await?;
-        } else {
-            file.write_all(b"\n").await?;
+        Some(Command::Info) => {
+            let formats = OutputFormat::value_variants().iter()
+                .filter_map(|format| format.to_possible_value().map(|value| value.get_name().to_string()))
+                .collect::<Vec<_>>();
+            // No optional cargo features exist yet; the list is kept so wrappers can rely on the key
+            let features: Vec<&str> = Vec::new();
+            println!("{}", json!({ "version": env!("CARGO_PKG_VERSION"), "formats": formats, "features": features }));
+            return Ok(());
+        }
+        Some(Command::Monitor { country, interval }) => {
+            monitor(&client, country, Duration::from_secs(*interval)).await;
+            return Ok(());
         }
+        None => {}
     }
-    file.write_all(b"}\n").await?;
 
-    Ok(())
+    if let Some(dir) = args.output_dir.clone() {
+        // Paths the user typed stay relative to where they typed them
+        let cwd = std::env::current_dir()?;
+        for path in [&mut args.dump_api, &mut args.from_server_info, &mut args.out_file].into_iter().flatten() {
+            *path = cwd.join(&*path);
+        }
+        if let Err(e) = std::fs::create_dir_all(&dir).and_then(|_| std::env::set_current_dir(&dir)) {
+            exit_if_not_writable(&cwd.join(&dir), &e);
+            return Err(e.into());
+        }
+    }
+
+    if let Some(name) = &args.save_location_preset {
+        return save_location_preset(&client, &args.presets_file, name).await;
+    }
+
+    if args.count_per_country {
+        let mut servers = load_servers(&args, &client).await?;
+        filter_servers(&args, &mut servers);
+        print_country_counts(&servers);
+        return Ok(());
+    }
+
+    // The lock is the first file a run writes, so a read-only directory shows up here
+    let _lock = if args.dry_validate {
+        None
+    } else {
+        match OutputLock::acquire(Path::new(".lock"), args.force) {
+            Ok(lock) => Some(lock),
+            Err(e) => {
+                if let Some(io_error) = e.downcast_ref::<io::Error>() {
+                    exit_if_not_writable(&std::env::current_dir().unwrap_or_default(), io_error);
+                }
+                return Err(e);
+            }
+        }
+    };
+
+    let token = match &args.token {
+        Some(token) => token.clone(),
+        None if args.non_interactive => return Err("No token given. Pass --token or set NORDVPN_TOKEN.".into()),
+        None => {
+            let mut token = String::new();
+            print!("Please enter your token: ");
+            io::stdout().flush().unwrap(); // Flush stdout to display the prompt before waiting for input
+            io::stdin().read_line(&mut token).unwrap();
+            token
+        }
+    };
+    let token = token.trim();
+    if !is_valid_token(token) {
+        return Err("The token should be 64 hexadecimal characters. Copy it again from Nord Account > NordVPN > Set up NordVPN manually.".into());
+    }
+
+    if args.dry_validate {
+        return dry_validate(&client, token).await;
+    }
+
+    let report = run(&args, &client, token).await?;
+    println!(
+        "Saved {} configs and {} best configs to {} in {}",
+        report.configs_written,
+        report.best_servers.len(),
+        report.output_dir.display(),
+        format_elapsed(report.elapsed, args.machine_time || !io::stdout().is_terminal())
+    );
+    for (call, elapsed) in &report.api_timings {
+        println!("  {:<14} {:.2}s", call, elapsed.as_secs_f64());
+    }
+
+    if report.errors.is_empty() {
+        match &args.post_run {
+            Some(_) if !report.wrote_output => println!("Skipping the post-run command, this run wrote nothing"),
+            Some(command) => run_post_command(command, &report.output_dir).await?,
+            None => {}
+        }
+        Ok(())
+    } else {
+        Err(format!("{} configs failed to save, see errors.log for details", report.errors.len()).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALID_KEY: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+
+    fn server(name: &str, key: &str, load: f64) -> Value {
+        json!({
+            "name": name,
+            "load": load,
+            "station": "192.0.2.1",
+            "locations": [{ "latitude": 0.0, "longitude": 0.0, "country": { "name": "Testland", "code": "TL", "city": { "name": "Testville" } } }],
+            "technologies": [{ "identifier": "wireguard_udp", "metadata": [{ "name": "public_key", "value": key }] }],
+        })
+    }
+
+    #[test]
+    fn filter_servers_skips_malformed_public_keys() {
+        let args = Args::try_parse_from(["nordvpn"]).unwrap();
+        let mut servers = vec![server("good", VALID_KEY, 10.0), server("short", "AAAA", 10.0), server("garbage", "not base64!", 10.0)];
+        filter_servers(&args, &mut servers);
+        let names = servers.iter().map(|server| server["name"].as_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(names, ["good"]);
+    }
+
+    #[test]
+    fn to_ansible_vars_writes_an_empty_list_for_no_servers() {
+        let yaml = to_ansible_vars(&json!({ "allowed_ips": "0.0.0.0/0, ::/0", "servers": [] }));
+        assert!(yaml.contains("nordvpn_servers: []\n"), "{}", yaml);
+    }
+
+    fn user_config() -> UserConfig {
+        UserConfig {
+            endpoint_override: None,
+            endpoint_family: EndpointFamily::Ipv4,
+            keepalive: 25,
+            base64: false,
+            fwmark: None,
+            dns: vec![DEFAULT_DNS],
+            dns_search: Vec::new(),
+            use_ip: true,
+            save_config: None,
+            allowed_ips: "0.0.0.0/0, ::/0".to_string(),
+            country_routes: false,
+            name_comment: false,
+            endpoint_both: false,
+            only_changed: false,
+            format: OutputFormat::WgQuick,
+            amnezia: AmneziaParams { jc: 4, jmin: 40, jmax: 70, s1: 0, s2: 0, h1: 1, h2: 2, h3: 3, h4: 4 },
+        }
+    }
+
+    #[test]
+    fn estimate_output_size_counts_configs_and_servers_json() {
+        let servers = [server("Testland #1", VALID_KEY, 10.0), server("Testland #2", VALID_KEY, 20.0)];
+        let mut config = user_config();
+        let config_len = generate_config(VALID_KEY, &servers[0], &config).unwrap().3.len() as u64;
+        let server_info = 2 * ("Testland #1".len() as u64 + 20);
+        assert_eq!(estimate_output_size(VALID_KEY, &servers, &config, 3, false), Some(3 * config_len + server_info));
+
+        config.base64 = true;
+        let with_copy = config_len + config_len.div_ceil(3) * 4;
+        assert_eq!(estimate_output_size(VALID_KEY, &servers, &config, 3, false), Some(3 * with_copy + server_info));
+        assert_eq!(estimate_output_size(VALID_KEY, &[], &config, 3, false), None);
+    }
+
+    #[test]
+    fn format_size_picks_a_readable_unit() {
+        assert_eq!(format_size(999), "999 B");
+        assert_eq!(format_size(1_500), "1.5 kB");
+        assert_eq!(format_size(2_340_000), "2.3 MB");
+        assert_eq!(format_size(5_000_000_000), "5.0 GB");
+    }
+
+    #[test]
+    fn load_json_writes_whole_loads_as_integers() {
+        assert_eq!(serde_json::to_string(&json!(["Germany #123", load_json(12.0)])).unwrap(), r#"["Germany #123",12]"#);
+        assert_eq!(load_json(12.5), json!(12.5));
+    }
+
+    #[test]
+    fn exclude_address_splits_only_the_containing_range() {
+        let v4 = "10.0.0.1".parse().unwrap();
+        assert_eq!(exclude_address("10.0.0.0/30", v4), ["10.0.0.2/31", "10.0.0.0/32"]);
+        assert_eq!(exclude_address("192.168.0.0/16", v4), ["192.168.0.0/16"]);
+        assert_eq!(exclude_address("2001:db8::/126", v4), ["2001:db8::/126"]);
+        let v6 = "2001:db8::".parse().unwrap();
+        assert_eq!(exclude_address("2001:db8::/127", v6), ["2001:db8::1/128"]);
+        assert_eq!(exclude_address("10.0.0.1/32", v4), Vec::<String>::new());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shell_quote_keeps_special_characters_literal() {
+        assert_eq!(shell_quote(Path::new("/srv/wire guard")), "'/srv/wire guard'");
+        assert_eq!(shell_quote(Path::new("/srv/it's $HOME")), "'/srv/it'\\''s $HOME'");
+    }
+
+    #[test]
+    fn is_valid_key_rejects_wrong_length_and_garbage() {
+        assert!(is_valid_key(VALID_KEY));
+        assert!(!is_valid_key(&STANDARD.encode([0u8; 31])));
+        assert!(!is_valid_key("not base64!"));
+        assert!(!is_valid_key(""));
+    }
+
+    #[test]
+    fn sort_servers_breaks_ties_by_name() {
+        let servers = vec![server("b", VALID_KEY, 20.0), server("a", VALID_KEY, 20.0)];
+        let sorted = sort_servers(servers, Some((10.0, 10.0)), false);
+        let names = sorted.iter().map(|server| server["name"].as_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b"]);
+    }
+
+    #[test]
+    fn top_per_region_keeps_lowest_load_per_region() {
+        let in_region = |name: &str, load: f64, region: &str| {
+            let mut server = server(name, VALID_KEY, load);
+            server["groups"] = json!([{ "title": region, "type": { "identifier": "regions" } }]);
+            server
+        };
+        let servers = vec![
+            in_region("eu-high", 80.0, "Europe"),
+            in_region("eu-low", 10.0, "Europe"),
+            in_region("eu-mid", 40.0, "Europe"),
+            in_region("am-only", 50.0, "The Americas"),
+        ];
+        let top = top_per_region(&servers, 2);
+        let names = |region: &str| top[region].iter().map(|server| server["name"].as_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(names("Europe"), ["eu-low", "eu-mid"]);
+        assert_eq!(names("The Americas"), ["am-only"]);
+    }
 }